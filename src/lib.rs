@@ -1,3 +1,14 @@
+#![allow(
+    clippy::comparison_to_empty,
+    clippy::len_zero,
+    clippy::match_like_matches_macro,
+    clippy::needless_borrow,
+    clippy::needless_late_init,
+    clippy::redundant_field_names,
+    clippy::unwrap_or_default,
+    clippy::useless_format
+)]
+
 #[cfg(test)]
 #[macro_use]
 extern crate indoc;
@@ -5,20 +16,165 @@ extern crate indoc;
 #[macro_use]
 extern crate lazy_static;
 
+use pulldown_cmark::escape::{escape_href, escape_html};
 use pulldown_cmark::{html, CodeBlockKind, CowStr, Event, LinkType, Options, Parser, Tag};
 use regex::Regex;
 use url::{ParseError, Url};
 
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
 use std::fmt;
-use std::path::{Path, PathBuf};
+use std::ops::{Range, RangeInclusive};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Markdown {
     pub as_html: String,
+    /// The HTML before sanitizing, if `ParseOptions::keep_raw_html` is set,
+    /// e.g. to find out why some markup went missing.
+    pub raw_html: Option<String>,
     pub headings: Vec<Heading>,
     pub links: Vec<Link>,
+    pub images: Vec<Image>,
+    pub code_blocks: Vec<CodeBlock>,
+    /// The ids of elements in raw HTML that keep them, see
+    /// `ParseOptions::id_elements`.
+    pub element_ids: Vec<String>,
+    /// Local links whose path is not in `ParseOptions::valid_paths`.
+    pub broken_links: Vec<Link>,
+    /// Likely mistakes found in the document, which didn't stop it from
+    /// being rendered.
+    pub warnings: Vec<Warning>,
+}
+
+impl Markdown {
+    /// The links on the page without duplicates, in the order they first
+    /// appear. Links count as the same when their resolved URLs are equal,
+    /// ignoring `.` path segments, so `./page` and `page` are one link.
+    pub fn unique_links(&self) -> Vec<UniqueLink> {
+        let mut unique: Vec<UniqueLink> = Vec::new();
+
+        for link in &self.links {
+            match unique
+                .iter_mut()
+                .find(|seen| same_target(&seen.url, &link.url))
+            {
+                Some(seen) => seen.count += 1,
+                None => unique.push(UniqueLink {
+                    url: link.url.clone(),
+                    title: link.title.clone(),
+                    count: 1,
+                }),
+            }
+        }
+
+        unique
+    }
+
+    /// The first image in the document, e.g. for social previews. Its source
+    /// has already been rewritten.
+    pub fn first_image(&self) -> Option<&Image> {
+        self.images.first()
+    }
+
+    /// Every fragment links can point to on the page: the heading anchors
+    /// and element ids.
+    pub fn anchors(&self) -> Vec<&str> {
+        self.headings
+            .iter()
+            .map(|heading| heading.anchor.as_str())
+            .chain(self.element_ids.iter().map(String::as_str))
+            .collect()
+    }
+}
+
+/// A link target along with how often the page links to it.
+#[derive(Debug, PartialEq, Clone)]
+pub struct UniqueLink {
+    pub url: UrlType,
+    /// The title of the first link to the target.
+    pub title: String,
+    pub count: usize,
+}
+
+/// Something in the document that is likely a mistake.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Warning {
+    pub kind: WarningKind,
+    /// The byte range in the markdown source the warning refers to.
+    pub range: Range<usize>,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[non_exhaustive]
+pub enum WarningKind {
+    /// A link to a fragment of the current document that doesn't match any
+    /// anchor in it. Holds the fragment.
+    BrokenAnchor(String),
+    /// A link whose path goes above the site root with `..` segments. Holds
+    /// the path.
+    EscapesRoot(String),
+    /// An image without alt text. Holds the image source as written.
+    MissingAltText(String),
+    /// A `csv` or `tsv` code block that couldn't be rendered as a table.
+    /// Holds the parse error.
+    InvalidCsv(String),
+    /// A code block embedding a file that the file loader couldn't load.
+    /// Holds the path.
+    MissingEmbed(String),
+    /// A code block embedding lines that aren't in the file. Holds the
+    /// line range.
+    InvalidEmbedLines(String),
+    /// An emoji shortcode that isn't known. Holds the shortcode without the
+    /// colons.
+    UnknownEmoji(String),
+    /// An element id that is also the anchor of a heading. Holds the id.
+    DuplicateAnchor(String),
+    /// A footnote reference without a definition. Holds the footnote's
+    /// name.
+    UndefinedFootnote(String),
+}
+
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WarningKind::BrokenAnchor(fragment) => {
+                write!(f, "link to missing anchor \"#{}\"", fragment)
+            }
+            WarningKind::EscapesRoot(path) => {
+                write!(f, "link path \"{}\" goes above the site root", path)
+            }
+            WarningKind::MissingAltText(src) => {
+                write!(f, "image \"{}\" has no alt text", src)
+            }
+            WarningKind::InvalidCsv(error) => {
+                write!(f, "could not render CSV as a table: {}", error)
+            }
+            WarningKind::MissingEmbed(path) => {
+                write!(f, "embedded file \"{}\" could not be loaded", path)
+            }
+            WarningKind::InvalidEmbedLines(lines) => {
+                write!(f, "embedded lines \"{}\" are not in the file", lines)
+            }
+            WarningKind::UnknownEmoji(identifier) => {
+                write!(f, "unknown emoji \":{}:\"", identifier)
+            }
+            WarningKind::DuplicateAnchor(id) => {
+                write!(f, "id \"{}\" is also the anchor of a heading", id)
+            }
+            WarningKind::UndefinedFootnote(name) => {
+                write!(f, "footnote \"[^{}]\" is not defined", name)
+            }
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -30,14 +186,177 @@ pub struct Heading {
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Link {
+    /// The text of the link. The link's title attribute is not included.
     pub title: String,
     pub url: UrlType,
+    pub kind: LinkSyntax,
+    /// The byte range of the whole link in the markdown source.
+    pub range: Range<usize>,
+    /// The anchor of the heading the link appears under, or `None` for links
+    /// before the first heading. Links inside a heading belong to that
+    /// heading's section.
+    pub section_anchor: Option<String>,
+    /// The URL exactly as written in the markdown, before any rewriting.
+    pub original: String,
+}
+
+/// A code block in the document, fenced or indented.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CodeBlock {
+    /// The language of a fenced code block, i.e. the first word of its info
+    /// string. `None` for indented code blocks.
+    pub lang: Option<String>,
+    /// The whole info string, e.g. `rust title="main.rs"`.
+    pub info: String,
+    /// The `key=value` pairs in the info string. Values may be quoted to
+    /// hold spaces, as in `title="Getting started"`, and pairs may also be
+    /// given in braces, as in `{cwd=/srv, run=false}`. Numbers and ranges in
+    /// braces are lines to highlight, e.g. `{3,5-7}`.
+    pub attributes: HashMap<String, String>,
+    /// The words in the info string after the language that aren't
+    /// `key=value` pairs, like `linenos`.
+    pub flags: Vec<String>,
+    /// The code exactly as written, without any escaping, or the contents
+    /// of the embedded file. See [`FileLoader`].
+    pub content: String,
+    /// Whether the block is a mermaid diagram or math rather than code.
+    pub is_diagram: bool,
+    /// The byte range of the whole block in the markdown source.
+    pub range: Range<usize>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Image {
+    /// The image source, after rewriting.
+    pub src: UrlType,
+    pub alt: String,
+    pub title: Option<String>,
+    /// The byte range of the whole image in the markdown source.
+    pub range: Range<usize>,
+    /// The source exactly as written in the markdown, before any rewriting.
+    pub original: String,
+    /// The size given with the `=WxH` suffix, e.g. `![alt](cat.png =640x360)`.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Where the link wrapping the image points, e.g. to the full size
+    /// version of a thumbnail.
+    pub link: Option<UrlType>,
+}
+
+/// A link to a file or page on the current site, split into its parts.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LocalUrl {
+    pub path: PathBuf,
+    /// The query string, without the leading `?`.
+    pub query: Option<String>,
+    /// The fragment, without the leading `#`.
+    pub fragment: Option<String>,
+}
+
+impl From<&str> for LocalUrl {
+    fn from(url: &str) -> Self {
+        let (rest, fragment) = match url.split_once('#') {
+            Some((rest, fragment)) => (rest, Some(fragment.to_owned())),
+            None => (url, None),
+        };
+
+        let (path, query) = match rest.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_owned())),
+            None => (rest, None),
+        };
+
+        LocalUrl {
+            path: PathBuf::from(normalize_dot_segments(path).as_ref()),
+            query,
+            fragment,
+        }
+    }
+}
+
+/// Resolves `.` and `..` segments in a URL path, without looking at the
+/// filesystem. A `..` that would go above the start of the path is kept, so
+/// links escaping the site root can still be spotted.
+fn normalize_dot_segments(path: &str) -> Cow<'_, str> {
+    let has_dot_segments = path
+        .split('/')
+        .any(|segment| segment == "." || segment == "..");
+
+    if !has_dot_segments {
+        return Cow::Borrowed(path);
+    }
+
+    let mut segments: Vec<&str> = Vec::new();
+
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." if matches!(segments.last(), Some(last) if *last != "..") => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalized = String::with_capacity(path.len());
+
+    if path.starts_with('/') {
+        normalized.push('/');
+    }
+    normalized.push_str(&segments.join("/"));
+
+    let ends_in_directory = path.ends_with('/') || path.ends_with("/.") || path.ends_with("/..");
+    if ends_in_directory && !segments.is_empty() {
+        normalized.push('/');
+    }
+
+    if normalized.is_empty() {
+        normalized.push('.');
+    }
+
+    Cow::Owned(normalized)
+}
+
+/// The markdown syntax a link was written in.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LinkSyntax {
+    /// `[foo](bar)`
+    Inline,
+    /// `[foo][bar]`
+    Reference,
+    /// `[foo][]`
+    Collapsed,
+    /// `[foo]`
+    Shortcut,
+    /// `<https://example.com>`
+    Autolink,
+    /// `<john@example.com>`
+    Email,
+}
+
+impl From<LinkType> for LinkSyntax {
+    fn from(link_type: LinkType) -> Self {
+        match link_type {
+            LinkType::Inline => LinkSyntax::Inline,
+            LinkType::Reference | LinkType::ReferenceUnknown => LinkSyntax::Reference,
+            LinkType::Collapsed | LinkType::CollapsedUnknown => LinkSyntax::Collapsed,
+            LinkType::Shortcut | LinkType::ShortcutUnknown => LinkSyntax::Shortcut,
+            LinkType::Autolink => LinkSyntax::Autolink,
+            LinkType::Email => LinkSyntax::Email,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[non_exhaustive]
 pub enum UrlType {
-    Local(PathBuf),
+    Local(LocalUrl),
     Remote(Url),
+    /// A `mailto:` link. Holds the email address, without the scheme or any
+    /// query parameters such as the subject.
+    Email(String),
+    /// A link to a fragment within the current page, e.g. `#installation`.
+    /// Holds the fragment without the leading `#`.
+    Anchor(String),
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -107,20 +426,656 @@ impl fmt::Display for CalloutKind {
 pub struct ParseOptions {
     /// Changes the root URL for any links that point to the current domain.
     pub url_root: String,
-    pub link_rewrite_rules: HashMap<String, String>,
+    /// Root URL for local image sources, for sites that serve images from
+    /// somewhere other than the pages. Falls back to `url_root` when unset.
+    pub image_root: Option<String>,
+    /// Replaces link and image URLs that match a key with the corresponding
+    /// value. Keys ending in `/` are prefix rules: they match any URL that
+    /// starts with the key, and the rest of the URL is appended to the
+    /// replacement. Exact matches take precedence over prefix rules, and the
+    /// longest matching prefix wins.
+    ///
+    /// Replacements may contain the placeholders `{url}` (the original URL),
+    /// `{path}` (the part after the matched prefix) and `{filename}` (the
+    /// last path segment). A prefix rule whose replacement uses placeholders
+    /// does not get the rest of the URL appended automatically.
+    ///
+    /// URLs and keys are matched after normalizing local paths: duplicate
+    /// slashes are collapsed and `.` segments dropped, so a `/assets/cat.jpg`
+    /// rule also matches `./assets/cat.jpg` and `/assets//cat.jpg`. Links
+    /// that match no rule keep their original form.
+    ///
+    /// `url_params` are appended after rewriting, and only if the rewritten
+    /// URL still points to the local domain.
+    ///
+    /// A rule can also set the title, i.e. hover text, of the links and
+    /// images it rewrites, see [`LinkRewrite`]. Plain strings convert into
+    /// rules without a title.
+    pub link_rewrite_rules: HashMap<String, LinkRewrite>,
+    /// Treats `/guide` and `/guide/` as the same URL when matching
+    /// `link_rewrite_rules`. Defaults to `false`.
+    pub rewrite_rules_ignore_trailing_slash: bool,
+    /// Regular expression rewrite rules, tried in order after
+    /// `link_rewrite_rules`. The first rule whose pattern matches the URL
+    /// rewrites it.
+    pub regex_rewrite_rules: Vec<RegexRewriteRule>,
+    /// Whether `url_root` is applied to URLs produced by rewrite rules.
+    /// Defaults to `false`, i.e. rewritten URLs are used as is. Remote URLs
+    /// never get the root either way.
+    pub url_root_after_rewrite: bool,
+    /// Query parameters appended to every link that points to the local
+    /// domain.
     pub url_params: HashMap<String, String>,
+    /// Also appends `url_params` to local image sources, e.g. for cache
+    /// busting.
+    pub url_params_on_images: bool,
+    /// Wraps images that are alone in their paragraph and have a title in a
+    /// `<figure>`, with the title as its `<figcaption>`.
+    pub image_figures: bool,
+    /// Highlights the code in fenced code blocks while parsing, instead of
+    /// leaving it to the browser. Blocks in languages that aren't known are
+    /// left as they are.
+    #[cfg(feature = "syntax-highlighting")]
+    pub syntax_highlighting: Option<SyntaxHighlighting>,
+    /// Wraps every line of code blocks in a `<span class="line">`, with its
+    /// number in a `data-line` attribute for the stylesheet to show. Single
+    /// code blocks can opt in with a `linenos` flag after the language,
+    /// e.g. ```` ```toml linenos ````.
+    ///
+    /// Lines listed in braces after the language, e.g. ```` ```rust {3,5-7} ````,
+    /// get an extra `hl-line` class either way.
+    pub code_line_numbers: bool,
+    /// Wraps fenced code blocks in a `<div>` with this class and the
+    /// language in a `data-lang` attribute, e.g.
+    /// `<div class="code-container" data-lang="rust">`, for themes that add
+    /// a copy button to code.
+    pub code_container_class: Option<String>,
+    /// The language of fenced code blocks that don't name one, and of
+    /// indented code blocks, e.g. `"text"` for a `language-text` class.
+    pub default_code_language: Option<String>,
+    /// Expands tabs in code blocks to spaces, with tab stops this many
+    /// columns apart. Diagrams are left alone.
+    pub code_tab_width: Option<u8>,
+    /// A class for inline code spans, to style them apart from code blocks.
+    pub inline_code_class: Option<String>,
+    /// Wraps the `$ ` prompts in `console`, `shell`, `bash` and `sh` code
+    /// blocks in a `<span class="prompt">`, so the stylesheet can keep them
+    /// out of selections when copying. Lines without a prompt are output,
+    /// and get an `output` class.
+    pub shell_prompts: bool,
+    /// Code blocks in these languages are diagrams, drawn by a script in
+    /// the browser. They're rendered in a `<pre>` with the class mapped to
+    /// the language, e.g. ```` ```mermaid ```` as `<pre class="mermaid">`.
+    /// Defaults to just mermaid, and an empty map renders all of them as
+    /// code.
+    pub diagram_languages: HashMap<String, String>,
+    /// Mermaid configuration added to every mermaid diagram as an init
+    /// directive, e.g. `{"theme": "forest"}`. Diagrams with their own
+    /// `%%{init: ...}%%` are left alone.
+    pub mermaid_init: Option<String>,
+    /// Maps language names to the one to use instead, e.g. `js` to
+    /// `javascript`, for the `language-` class, highlighting and diagrams.
+    /// [`CodeBlock::lang`] keeps the name as written.
+    pub language_aliases: HashMap<String, String>,
+    /// Converts emoji shortcodes like `:smile:` outside of code into emoji.
+    /// On by default.
+    pub emoji: bool,
+    /// Extra emoji shortcodes, without the colons, and what to replace them
+    /// with, e.g. `shipit` to `CustomEmoji::Text("🐿️")`. These take
+    /// precedence over the built-in ones.
+    pub custom_emoji: HashMap<String, CustomEmoji>,
+    /// Which images open in the theme's lightbox when clicked. Such images
+    /// get a `data-zoomable` attribute and are wrapped in an
+    /// `<a class="zoom">` pointing at the image itself.
+    pub zoomable_images: ZoomableImages,
+    /// Warns about images without alt text. Defaults to `true`.
+    pub warn_missing_alt_text: bool,
+    /// The URL schemes allowed in links, images and media sources, e.g.
+    /// `doctave` for `doctave://project/page`. Defaults to the sanitizer's
+    /// own list, which includes `http`, `https` and `mailto`. Scripting
+    /// schemes like `javascript` are never allowed, and neither is `data`;
+    /// see `allow_data_image_urls` for that.
+    pub url_schemes: HashSet<String>,
+    /// Allows images with `data:` URL sources, such as small embedded PNGs.
+    /// Only `image/*` media types are kept, and only on images. Defaults to
+    /// `false`, which strips them.
+    pub allow_data_image_urls: bool,
+    /// Cleans the HTML of anything that isn't allowed, such as scripts. On
+    /// by default.
+    ///
+    /// **Only turn this off for trusted content.** Without it, any HTML in
+    /// the markdown ends up in the page as is, including `<script>` tags
+    /// and `onclick` handlers, which lets whoever wrote the markdown run
+    /// code in the browsers of everyone reading it.
+    pub sanitize: bool,
+    /// Allows raw `<details>` and `<summary>` blocks, including the `open`
+    /// attribute. On by default; when off, only their contents are kept.
+    pub allow_details: bool,
+    /// Hosts that raw `<iframe>` embeds may load from, e.g. `youtube.com`,
+    /// which also covers its subdomains like `www.youtube.com`. The `src`
+    /// must be an `http(s)` URL whose parsed host matches; any other iframe
+    /// is removed. Empty by default, which removes all iframes.
+    pub allowed_iframe_hosts: Vec<String>,
+    /// Allows raw `<video>` and `<audio>` elements, with `<source>` and
+    /// `<track>` children. Local `src` and `poster` URLs are rewritten like
+    /// image sources; remote ones must be on `allowed_media_hosts`, or are
+    /// removed. Defaults to `false`.
+    pub media_elements: bool,
+    /// Hosts that media elements may load from, matched like
+    /// `allowed_iframe_hosts`.
+    pub allowed_media_hosts: Vec<String>,
+    /// Allows raw `<picture>` elements with `<source>` children, for
+    /// responsive images. Sources may have `srcset`, `media` and `type`
+    /// attributes, and their URLs must be relative or `http(s)`. Defaults
+    /// to `false`.
+    pub picture_elements: bool,
+    /// Custom elements, i.e. web components like `doc-callout`, to let
+    /// through the sanitizer with only the given attributes and classes.
+    /// Names without a hyphen aren't custom elements and are ignored.
+    /// Markdown inside them is rendered as in any other HTML block.
+    pub custom_elements: HashMap<String, CustomElement>,
+    /// Elements in raw HTML that may keep their `id`, e.g. `div`, `table`
+    /// and `figure`, so that links can point at them. Ids must be slugs
+    /// like `pricing-table`, or are removed. The ids are collected in
+    /// `Markdown::element_ids` and count as anchors when checking links.
+    /// Empty by default.
+    pub id_elements: Vec<String>,
+    /// Which HTML comments are kept by the sanitizer, e.g. for processing
+    /// markers like `<!-- ad-slot -->`. Removes them all by default.
+    pub html_comments: HtmlComments,
+    /// Keeps the HTML from before sanitizing in `Markdown::raw_html`, for
+    /// debugging or for running a sanitizer of your own. Defaults to
+    /// `false`.
+    pub keep_raw_html: bool,
+    /// HTML tags to allow on top of the built-in ones, e.g. `"abbr"`.
+    /// `form`, `script` and `style` are always removed with their contents.
+    pub extra_allowed_tags: Vec<String>,
+    /// Attributes to allow on top of the built-in ones, by tag, e.g.
+    /// `"abbr"` to `["title"]`.
+    pub extra_tag_attributes: HashMap<String, Vec<String>>,
+    /// Classes to allow on top of the built-in ones, by tag, e.g. `"span"`
+    /// to `["badge", "since"]` for inline styles. Other classes are removed,
+    /// but the element and its contents are kept. Tags that already allow
+    /// any class are unaffected.
+    pub extra_allowed_classes: HashMap<String, Vec<String>>,
+    /// URLs starting with any of these prefixes never get `url_params`
+    /// appended, e.g. signed URLs that break when the query changes. The
+    /// prefixes are matched against the URL after rewriting.
+    pub url_params_exempt_prefixes: Vec<String>,
+    /// The paths of every page and asset that exists on the site. When set,
+    /// local links whose path, after rewriting, is not in the set are
+    /// reported in `Markdown::broken_links`.
+    pub valid_paths: Option<HashSet<PathBuf>>,
+    /// The URL path of the document being parsed, after `url_root`, e.g.
+    /// `/docs/guide/install`. Fragments of local links to this path are
+    /// checked against the document's anchors, like fragment-only links.
+    pub current_path: Option<PathBuf>,
+    /// Makes every local link and image absolute by resolving it against
+    /// this URL, after `url_root` and rewrite rules, e.g. for feeds. The
+    /// links are then collected as `UrlType::Remote`, but still count as
+    /// internal. Fragment-only links stay as they are.
+    pub base_url: Option<Url>,
+    /// Opens links to other sites in a new tab, with
+    /// `rel="noopener noreferrer"` so the opened page can't reach back into
+    /// ours.
+    pub external_links_in_new_tab: bool,
+    /// Adds `rel="nofollow"` to links to other sites, e.g. for user
+    /// contributed content.
+    pub nofollow_external_links: bool,
+    /// A CSS class added to links to other sites. Only this class is allowed
+    /// on links by the sanitizer.
+    pub external_link_class: Option<String>,
+    /// An HTML snippet inserted at the end of links to other sites, e.g.
+    /// `<span class="external-icon" aria-hidden="true"></span>`. Classes on
+    /// `span` elements in the snippet, and the `aria-hidden` attribute, are
+    /// allowed by the sanitizer. Links wrapping an image don't get the icon.
+    pub external_link_icon: Option<String>,
+    /// Turns bare `http(s)://` and `www.` URLs in text into links, like
+    /// GitHub Flavored Markdown does.
+    pub autolink_bare_urls: bool,
+    /// Enables `[^1]` footnote references and `[^1]: ...` definitions.
+    /// Footnotes are numbered in the order they're first referenced, and
+    /// listed in a `<section class="footnotes">` at the end of the document,
+    /// with links back to the references. Definitions that are never
+    /// referenced are left out, and references without a definition stay
+    /// as text, with a warning.
+    ///
+    /// Footnote references are not links, so they are never collected into
+    /// `Markdown::links`.
+    pub footnotes: bool,
+    /// Turns straight quotes into curly ones, `--` and `---` into en and
+    /// em dashes, and `...` into an ellipsis, outside of code. Heading
+    /// anchors are the same either way.
+    pub smart_punctuation: bool,
+    /// Enables definition lists, like in PHP Markdown Extra: a paragraph of
+    /// terms, one per line, followed by definitions that start with `:`.
+    ///
+    /// ```markdown
+    /// Apple
+    /// : A fruit.
+    /// : A company.
+    /// ```
+    ///
+    /// Definitions continue with indented lines, as list items do, so they
+    /// can hold several paragraphs. Definitions separated by blank lines
+    /// have their text wrapped in paragraphs.
+    pub definition_lists: bool,
+    /// Enables admonitions, written as `:::` containers:
+    ///
+    /// ```markdown
+    /// :::warning Mind the gap
+    /// Any *markdown*, including other admonitions.
+    /// :::
+    /// ```
+    ///
+    /// They become a `<div class="admonition warning">` holding a
+    /// `<p class="admonition-title">` and the contents. Without a title, the
+    /// type is used, capitalized. Each `:::` closes the innermost open
+    /// container, and fences that aren't matched stay as text.
+    pub admonitions: bool,
+    /// The admonition types, which are added as classes next to
+    /// `admonition`. Containers of other types only get the `admonition`
+    /// class. Defaults to `note`, `tip`, `warning` and `danger`.
+    pub admonition_types: HashSet<String>,
+    /// Enables collapsible sections, written as `:::details` containers,
+    /// which become `details` elements:
+    ///
+    /// ```markdown
+    /// :::details Click to expand
+    /// Any *markdown*.
+    /// :::
+    /// ```
+    ///
+    /// `:::details open Title` renders the section expanded. Without a
+    /// title, the summary is "Details". They share the `:::` syntax with
+    /// admonitions, and can be nested in each other. Like raw `details`
+    /// elements, they are removed by the sanitizer unless `allow_details`
+    /// is on.
+    pub collapsible_sections: bool,
+    /// Leaves headings in collapsible sections out of `Markdown::headings`,
+    /// e.g. so that they don't show up in a table of contents. Their anchors
+    /// are collected in `Markdown::element_ids` instead, so that links to
+    /// them still work.
+    pub exclude_collapsible_headings: bool,
+    /// Enables abbreviations, like in PHP Markdown Extra. A line like
+    /// `*[HTML]: HyperText Markup Language` is removed, and the word `HTML`
+    /// is wrapped in `<abbr title="HyperText Markup Language">` wherever it
+    /// appears in text, but not in code, image alt text or URLs. Matching is
+    /// case-sensitive, and only whole words match.
+    ///
+    /// Definitions apply to the whole document, wherever they are. When an
+    /// abbreviation is defined more than once, the last definition wins, and
+    /// an empty one like `*[HTML]:` removes it. Where abbreviations overlap,
+    /// like `HTML` and `HTML5`, the longest one wins.
+    pub abbreviations: bool,
+    /// Caller-supplied callbacks for customizing parsing.
+    pub hooks: Hooks,
 }
 
 impl Default for ParseOptions {
     fn default() -> Self {
         ParseOptions {
             url_root: String::from("/"),
+            image_root: None,
             link_rewrite_rules: HashMap::new(),
+            rewrite_rules_ignore_trailing_slash: false,
+            regex_rewrite_rules: Vec::new(),
+            url_root_after_rewrite: false,
             url_params: HashMap::new(),
+            url_params_on_images: false,
+            image_figures: false,
+            zoomable_images: ZoomableImages::Never,
+            #[cfg(feature = "syntax-highlighting")]
+            syntax_highlighting: None,
+            code_line_numbers: false,
+            code_container_class: None,
+            default_code_language: None,
+            code_tab_width: None,
+            inline_code_class: None,
+            shell_prompts: false,
+            diagram_languages: vec![("mermaid".to_string(), "mermaid".to_string())]
+                .into_iter()
+                .collect(),
+            mermaid_init: None,
+            language_aliases: HashMap::new(),
+            emoji: true,
+            custom_emoji: HashMap::new(),
+            warn_missing_alt_text: true,
+            url_schemes: ammonia::Builder::default()
+                .clone_url_schemes()
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            allow_data_image_urls: false,
+            sanitize: true,
+            allow_details: true,
+            allowed_iframe_hosts: Vec::new(),
+            media_elements: false,
+            allowed_media_hosts: Vec::new(),
+            picture_elements: false,
+            custom_elements: HashMap::new(),
+            id_elements: Vec::new(),
+            html_comments: HtmlComments::Remove,
+            keep_raw_html: false,
+            extra_allowed_tags: Vec::new(),
+            extra_tag_attributes: HashMap::new(),
+            extra_allowed_classes: HashMap::new(),
+            url_params_exempt_prefixes: Vec::new(),
+            valid_paths: None,
+            current_path: None,
+            base_url: None,
+            external_links_in_new_tab: false,
+            nofollow_external_links: false,
+            external_link_class: None,
+            external_link_icon: None,
+            autolink_bare_urls: false,
+            footnotes: false,
+            smart_punctuation: false,
+            definition_lists: false,
+            admonitions: false,
+            admonition_types: ["note", "tip", "warning", "danger"]
+                .iter()
+                .map(|kind| kind.to_string())
+                .collect(),
+            collapsible_sections: false,
+            exclude_collapsible_headings: false,
+            abbreviations: false,
+            hooks: Hooks::default(),
+        }
+    }
+}
+
+/// Callbacks that let the caller take over parts of the parsing process.
+///
+/// Hooks are compared by identity: two `Hooks` are equal only if they share
+/// the same callbacks.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    /// Resolves link and image URLs before any other rewriting is applied.
+    pub link_resolver: Option<Arc<dyn LinkResolver>>,
+    /// Provides responsive variants of images, after all rewriting.
+    pub image_resolver: Option<Arc<dyn ImageResolver>>,
+    /// Loads the files embedded in code blocks with `src="..."`.
+    pub file_loader: Option<Arc<dyn FileLoader>>,
+    /// Changes the sanitizer's configuration.
+    pub sanitizer: Option<Arc<dyn SanitizerHook>>,
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("link_resolver", &self.link_resolver.is_some())
+            .field("image_resolver", &self.image_resolver.is_some())
+            .field("file_loader", &self.file_loader.is_some())
+            .field("sanitizer", &self.sanitizer.is_some())
+            .finish()
+    }
+}
+
+impl PartialEq for Hooks {
+    fn eq(&self, other: &Self) -> bool {
+        same_hook(&self.link_resolver, &other.link_resolver)
+            && same_hook(&self.image_resolver, &other.image_resolver)
+            && same_hook(&self.file_loader, &other.file_loader)
+            && same_hook(&self.sanitizer, &other.sanitizer)
+    }
+}
+
+fn same_hook<T: ?Sized>(a: &Option<Arc<T>>, b: &Option<Arc<T>>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// How highlighted code is styled.
+#[cfg(feature = "syntax-highlighting")]
+#[derive(Debug, PartialEq, Clone)]
+pub enum SyntaxHighlighting {
+    /// Spans with classes named after the syntax scopes, to be styled with
+    /// CSS, e.g. `<span class="hl-keyword hl-control">`. Only span classes
+    /// with the prefix get through the sanitizer.
+    Classes { prefix: String },
+    /// Spans with inline colors from one of syntect's bundled themes, such
+    /// as `"InspiredGitHub"`. This lets `style` attributes on spans through
+    /// the sanitizer.
+    Theme(String),
+}
+
+/// What a custom element may have on it.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct CustomElement {
+    /// The allowed attributes. A name ending in `*` allows every attribute
+    /// starting with the rest of it, e.g. `data-*`. Event handlers like
+    /// `onclick` and `style` are never allowed.
+    pub attributes: Vec<String>,
+    /// The allowed classes. Other classes are removed.
+    pub classes: Vec<String>,
+}
+
+/// What a custom emoji shortcode is replaced with.
+#[derive(Debug, PartialEq, Clone)]
+pub enum CustomEmoji {
+    /// Text, like a unicode emoji.
+    Text(String),
+    /// An image URL, shown as `<img class="custom-emoji">` with the
+    /// shortcode as its alt text. The URL is rewritten like other images.
+    Image(String),
+}
+
+/// Which HTML comments make it through the sanitizer. Comments can't hide
+/// anything else from it, as the HTML is parsed like a browser would.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum HtmlComments {
+    Remove,
+    Keep,
+    /// Comments whose text starts with the given prefix, ignoring leading
+    /// whitespace, e.g. `exclude-from-pdf` for
+    /// `<!-- exclude-from-pdf-start -->`.
+    KeepWithPrefix(String),
+}
+
+/// Which images can be zoomed. Images already inside a link never are.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ZoomableImages {
+    Never,
+    Always,
+    /// Local images whose `=WxH` size hint is wider than the given number of
+    /// pixels.
+    WiderThan(u32),
+}
+
+/// Whether a URL being resolved belongs to a link or an image.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LinkKind {
+    Link,
+    Image,
+}
+
+/// Maps URLs written in the markdown to the URLs that should be emitted.
+///
+/// The resolver is called for every link and image URL. Returning `None`
+/// falls back to the default behavior, i.e. the rewrite rules. A returned
+/// URL skips the rewrite rules, but is still subject to `url_root` and
+/// `url_params`.
+///
+/// Any `Fn(&str, LinkKind) -> Option<String>` closure can be used as a
+/// resolver.
+pub trait LinkResolver: Send + Sync {
+    fn resolve(&self, url: &str, kind: LinkKind) -> Option<String>;
+}
+
+impl<F> LinkResolver for F
+where
+    F: Fn(&str, LinkKind) -> Option<String> + Send + Sync,
+{
+    fn resolve(&self, url: &str, kind: LinkKind) -> Option<String> {
+        self(url, kind)
+    }
+}
+
+/// Provides the `srcset` and `sizes` attributes of an image, e.g. for
+/// resized variants produced by the build.
+///
+/// The resolver is called with the final source of every image. Returning
+/// `None` leaves the image as is.
+///
+/// Any `Fn(&str) -> Option<ImageVariants>` closure can be used as a
+/// resolver.
+pub trait ImageResolver: Send + Sync {
+    fn resolve(&self, src: &str) -> Option<ImageVariants>;
+}
+
+impl<F> ImageResolver for F
+where
+    F: Fn(&str) -> Option<ImageVariants> + Send + Sync,
+{
+    fn resolve(&self, src: &str) -> Option<ImageVariants> {
+        self(src)
+    }
+}
+
+/// Loads the contents of files embedded in code blocks, e.g.
+/// ```` ```rust src="examples/hello.rs" lines="3-20" ````, so that snippets
+/// in the docs stay in sync with the code they come from.
+///
+/// The loader is called with the `src` attribute as written. Returning
+/// `None` renders a placeholder and adds a warning.
+///
+/// Any `Fn(&str) -> Option<String>` closure can be used as a loader.
+pub trait FileLoader: Send + Sync {
+    fn load(&self, path: &str) -> Option<String>;
+}
+
+impl<F> FileLoader for F
+where
+    F: Fn(&str) -> Option<String> + Send + Sync,
+{
+    fn load(&self, path: &str) -> Option<String> {
+        self(path)
+    }
+}
+
+/// Changes the configuration of the sanitizer, for what the options don't
+/// cover.
+///
+/// **This can undo the sanitizer's protection.** Allowing the wrong tag or
+/// attribute lets whoever wrote the markdown run code in the browsers of
+/// everyone reading it.
+///
+/// Any `Fn(&mut ammonia::Builder)` closure can be used as a hook.
+pub trait SanitizerHook: Send + Sync {
+    /// Called with the sanitizer as configured by the options, just before
+    /// it cleans the HTML. The builder already has an attribute filter, so
+    /// setting another one panics; use `filter_attribute` instead.
+    fn configure(&self, builder: &mut ammonia::Builder<'_>);
+
+    /// Filters the attributes left after the crate's own checks, e.g. to
+    /// rewrite URLs. Returning `None` drops the attribute. Keeps every
+    /// attribute as it is by default.
+    fn filter_attribute<'u>(
+        &self,
+        _element: &str,
+        _attribute: &str,
+        value: &'u str,
+    ) -> Option<Cow<'u, str>> {
+        Some(value.into())
+    }
+}
+
+impl<F> SanitizerHook for F
+where
+    F: Fn(&mut ammonia::Builder<'_>) + Send + Sync,
+{
+    fn configure(&self, builder: &mut ammonia::Builder<'_>) {
+        self(builder)
+    }
+}
+
+/// Responsive variants of an image.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ImageVariants {
+    /// The `srcset` candidates, as URL and descriptor pairs, e.g.
+    /// `("/cat-480w.jpg", "480w")`.
+    pub srcset: Vec<(String, String)>,
+    /// The `sizes` attribute, e.g. `(max-width: 600px) 480px, 960px`.
+    pub sizes: Option<String>,
+}
+
+/// The replacement for links and images matching a key of
+/// `ParseOptions::link_rewrite_rules`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LinkRewrite {
+    /// The new URL.
+    pub url: String,
+    /// A title for the rewritten link or image, e.g. `Expires in 1 hour`.
+    /// Replaces any title written in the markdown.
+    pub title: Option<String>,
+}
+
+impl From<String> for LinkRewrite {
+    fn from(url: String) -> Self {
+        LinkRewrite { url, title: None }
+    }
+}
+
+impl From<&str> for LinkRewrite {
+    fn from(url: &str) -> Self {
+        url.to_owned().into()
+    }
+}
+
+/// A link rewrite rule that matches URLs against a regular expression.
+///
+/// The replacement may refer to capture groups using `$1` or `$name`
+/// syntax.
+#[derive(Debug, Clone)]
+pub struct RegexRewriteRule {
+    pattern: Regex,
+    replacement: String,
+    title: Option<String>,
+}
+
+impl RegexRewriteRule {
+    /// Builds a new rule, failing if the pattern is not a valid regular
+    /// expression.
+    pub fn new(pattern: &str, replacement: &str) -> Result<Self, regex::Error> {
+        Ok(RegexRewriteRule {
+            pattern: Regex::new(pattern)?,
+            replacement: replacement.to_owned(),
+            title: None,
+        })
+    }
+
+    /// Sets the title of the links and images the rule rewrites, like
+    /// `LinkRewrite::title`.
+    pub fn with_title(mut self, title: &str) -> Self {
+        self.title = Some(title.to_owned());
+        self
+    }
+
+    fn apply(&self, url: &str) -> Option<String> {
+        if self.pattern.is_match(url) {
+            Some(
+                self.pattern
+                    .replace(url, self.replacement.as_str())
+                    .into_owned(),
+            )
+        } else {
+            None
         }
     }
 }
 
+impl PartialEq for RegexRewriteRule {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern.as_str() == other.pattern.as_str()
+            && self.replacement == other.replacement
+            && self.title == other.title
+    }
+}
+
 pub fn parse(input: &str, opts: Option<ParseOptions>) -> Markdown {
     let parse_opts = opts.unwrap_or(ParseOptions::default());
 
@@ -129,38 +1084,370 @@ pub fn parse(input: &str, opts: Option<ParseOptions>) -> Markdown {
     options.insert(Options::ENABLE_TASKLISTS);
     options.insert(Options::ENABLE_TABLES);
 
-    let mut headings = vec![];
+    if parse_opts.footnotes {
+        options.insert(Options::ENABLE_FOOTNOTES);
+    }
+
+    if parse_opts.smart_punctuation {
+        options.insert(Options::ENABLE_SMART_PUNCTUATION);
+    }
+
+    let (input, image_sizes) = strip_image_sizes(input, options);
+    let (input, container_fences) = if parse_opts.admonitions || parse_opts.collapsible_sections {
+        mark_containers(input, options, &parse_opts)
+    } else {
+        (input, HashMap::new())
+    };
+    let (input, abbreviations) = if parse_opts.abbreviations {
+        strip_abbreviations(input, options)
+    } else {
+        (input, None)
+    };
+    let (input, definition_lines) = if parse_opts.definition_lists {
+        mark_definition_lists(input, options)
+    } else {
+        (input, HashSet::new())
+    };
+
+    let mut headings: Vec<Heading> = vec![];
     let mut links = vec![];
+    let mut images = vec![];
     let mut active_callout = None;
-    let mut current_link = None;
+    let mut current_link: Option<Link> = None;
+    let mut current_image: Option<Image> = None;
+    // The position of the current image's start event, and any attributes
+    // to add to it.
+    let mut image_start: Option<(usize, Vec<(&str, String)>)> = None;
+    // The event positions spanned by the last image, and its title.
+    let mut last_image: Option<(Range<usize>, String)> = None;
+    let mut pending_link_icon = false;
+    // The position of the current link's start event, and its final URL.
+    let mut link_start: Option<(usize, String)> = None;
     let mut current_heading: Option<Heading> = None;
-
-    let mut parser = Parser::new_ext(input, options).into_iter().peekable();
+    let mut heading_links_start = 0;
+    let mut in_code_block = false;
+    let mut code_block: Option<OpenCodeBlock> = None;
+    let mut code_blocks = Vec::new();
+    let mut tab_group: Option<TabGroup> = None;
+    let mut warnings = Vec::new();
+    let mut in_link = false;
+    // How many `<a>` tags written as HTML, and images, are open. Bare URLs in
+    // either aren't autolinked.
+    let mut open_html_links: usize = 0;
+    let mut open_images = 0;
+    let mut element_ids: Vec<(String, Range<usize>)> = Vec::new();
+    // Footnote references are filled in at the end, once all the
+    // definitions are known. They're kept as their event position, name and
+    // range. References in a definition move along with it, with positions
+    // relative to the start of the definition.
+    let mut footnote_references: Vec<FootnoteReference> = Vec::new();
+    let mut footnote_definitions: HashMap<String, (Vec<Event>, Vec<FootnoteReference>)> =
+        HashMap::new();
+    // The positions of the open footnote definitions' starts, and their
+    // names. Pulldown can nest definitions that aren't separated properly.
+    let mut footnote_starts: Vec<(usize, String)> = Vec::new();
+    // How many collapsible sections the current event is in.
+    let mut open_details = 0;
+    // Whether each of the open lists is a definition list.
+    let mut open_lists: Vec<bool> = Vec::new();
+
+    let mut parser = Parser::new_ext(&input, options)
+        .into_offset_iter()
+        .peekable();
+    // Events produced while processing the input, which still need to go
+    // through the same processing as the parser's own events.
+    let mut injected = VecDeque::new();
 
     let mut events = Vec::new();
 
-    while let Some(event) = parser.next() {
+    while let Some((event, range)) = injected.pop_front().or_else(|| parser.next()) {
+        match &event {
+            Event::Start(Tag::CodeBlock(_)) => in_code_block = true,
+            Event::End(Tag::CodeBlock(_)) => in_code_block = false,
+            Event::Start(Tag::Link(..)) => in_link = true,
+            Event::End(Tag::Link(..)) => in_link = false,
+            Event::Start(Tag::Image(..)) => open_images += 1,
+            Event::End(Tag::Image(..)) => open_images -= 1,
+            Event::Html(html) => {
+                for tag in HTML_LINK_TAG.captures_iter(html) {
+                    if tag.name("closing").is_some() {
+                        open_html_links = open_html_links.saturating_sub(1);
+                    } else {
+                        open_html_links += 1;
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        // Tab groups end at anything other than another tabbed code block.
+        if tab_group.is_some() && code_block.is_none() && !starts_tabbed_code_block(&event) {
+            close_tab_group(&mut events, tab_group.take().unwrap());
+        }
+
         match event {
-            // Mermaid JS code block tranformations
-            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(inner))) => {
-                let lang = inner.split(' ').next().unwrap();
-
-                if lang == "mermaid" {
-                    events.push(Event::Html(CowStr::Borrowed("<div class=\"mermaid\">\n")));
-                } else if lang == "math" {
-                    events.push(Event::Html(CowStr::Borrowed("<div class=\"math\">\n")));
-                } else {
-                    events.push(Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(inner))));
+            // Pulldown splits text at smart punctuation, which would add
+            // hyphens to heading anchors, so it's stitched back together.
+            Event::Text(text)
+                if parse_opts.smart_punctuation
+                    && current_heading.is_some()
+                    && injected.is_empty()
+                    && matches!(parser.peek(), Some((Event::Text(_), _))) =>
+            {
+                let mut text = text.into_string();
+                let mut range = range;
+                while let Some((Event::Text(next), next_range)) = parser.peek() {
+                    text.push_str(next);
+                    range.end = next_range.end;
+                    parser.next();
+                }
+
+                injected.push_front((Event::Text(text.into()), range));
+            }
+
+            Event::Text(text)
+                if parse_opts.autolink_bare_urls
+                    && !in_code_block
+                    && !in_link
+                    && open_html_links == 0
+                    && open_images == 0
+                    && BARE_URL.is_match(&text) =>
+            {
+                // Pulldown can split a URL into several text events, e.g. at
+                // underscores, so stitch them back together first.
+                let mut text = text.into_string();
+                let mut range = range;
+                while let Some((Event::Text(next), next_range)) = parser.peek() {
+                    text.push_str(next);
+                    range.end = next_range.end;
+                    parser.next();
+                }
+
+                injected.extend(autolink_bare_urls(&text, range));
+            }
+
+            // The contents of code blocks are gathered up and rendered at the end
+            // of the block.
+            Event::Text(text) if code_block.is_some() => {
+                code_block.as_mut().unwrap().code.push_str(&text);
+            }
+
+            // Custom emoji images become HTML, and the text around them goes
+            // through the usual processing. Image alt text stays text.
+            Event::Text(text)
+                if current_image.is_none() && !image_emojis(&text, &parse_opts).is_empty() =>
+            {
+                let mut split = Vec::new();
+                let mut position = 0;
+
+                for (emoji_range, url) in image_emojis(&text, &parse_opts) {
+                    if emoji_range.start > position {
+                        split.push((
+                            Event::Text(text[position..emoji_range.start].to_string().into()),
+                            (range.start + position).min(range.end)
+                                ..(range.start + emoji_range.start).min(range.end),
+                        ));
+                    }
+
+                    let url =
+                        image_url(CowStr::from(url.to_string()), LinkType::Inline, &parse_opts);
+
+                    let mut html = String::from("<img class=\"custom-emoji\" src=\"");
+                    escape_href(&mut html, &url).unwrap();
+                    html.push_str("\" alt=\"");
+                    escape_html(&mut html, &text[emoji_range.clone()]).unwrap();
+                    html.push_str("\">");
+
+                    split.push((
+                        Event::Html(html.into()),
+                        (range.start + emoji_range.start).min(range.end)
+                            ..(range.start + emoji_range.end).min(range.end),
+                    ));
+                    position = emoji_range.end;
+                }
+
+                if position < text.len() {
+                    split.push((
+                        Event::Text(text[position..].to_string().into()),
+                        (range.start + position).min(range.end)..range.end,
+                    ));
+                }
+
+                for event in split.into_iter().rev() {
+                    injected.push_front(event);
+                }
+            }
+
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let mut block = OpenCodeBlock::new(&kind, range);
+
+                if block.fence.language.is_empty() {
+                    if let Some(language) = &parse_opts.default_code_language {
+                        block.fence.language = language.clone();
+                    }
+                }
+
+                if let Some(language) = parse_opts.language_aliases.get(&block.fence.language) {
+                    block.fence.language = language.clone();
+                }
+
+                block.diagram_class = parse_opts
+                    .diagram_languages
+                    .get(&block.fence.language)
+                    .cloned();
+
+                if let Some(tab) = block.fence.tab() {
+                    let group = tab_group.get_or_insert_with(|| {
+                        // Filled in once all the tabs in the group are known.
+                        events.push(Event::Html(CowStr::Borrowed("")));
+                        TabGroup {
+                            start: events.len() - 1,
+                            labels: Vec::new(),
+                        }
+                    });
+                    group.labels.push(tab.to_string());
+
+                    let mut panel = String::from("<div class=\"code-tab-panel\" data-tab=\"");
+                    escape_html(&mut panel, tab).unwrap();
+                    panel.push_str("\">\n");
+                    events.push(Event::Html(panel.into()));
+                }
+
+                // Diagrams, like mermaid. Their scripts read the text content of
+                // a `<pre>`, where the browser has already decoded the escaped
+                // source.
+                match &block.diagram_class {
+                    Some(class) => {
+                        let mut html = String::from("<pre class=\"");
+                        escape_html(&mut html, class).unwrap();
+                        html.push_str("\">");
+                        events.push(Event::Html(html.into()));
+                    }
+                    None if block.fence.language == "math" => {
+                        events.push(Event::Html(CowStr::Borrowed("<div class=\"math\">\n")))
+                    }
+                    // Tables are only known to be valid once their contents
+                    // have been read, so they're opened at the end.
+                    None if block.is_table() => {}
+                    None => {
+                        let opening = code_block_opening(&block, &parse_opts);
+                        if !opening.is_empty() {
+                            events.push(Event::Html(opening.into()));
+                        }
+
+                        events.push(code_block_start(&block, kind));
+                    }
                 }
+
+                code_block = Some(block);
             }
-            Event::End(Tag::CodeBlock(CodeBlockKind::Fenced(inner))) => {
-                let lang = inner.split(' ').next().unwrap();
-                if lang == "mermaid" || lang == "math" {
-                    events.push(Event::Html(CowStr::Borrowed("</div>")));
+            Event::End(Tag::CodeBlock(kind)) => {
+                let mut block = code_block.take().unwrap();
+
+                if let Some(loader) = &parse_opts.hooks.file_loader {
+                    if let Err(kind) = embed_file(&mut block, loader.as_ref()) {
+                        warnings.push(Warning {
+                            kind,
+                            range: block.range.clone(),
+                        });
+                    }
+                }
+                let block_tab = block.fence.tab().is_some();
+                code_blocks.push(block.to_code_block());
+
+                let table = match block.is_table() {
+                    true => match csv_table(&block) {
+                        Ok(table) => Some(table),
+                        Err(error) => {
+                            warnings.push(Warning {
+                                kind: WarningKind::InvalidCsv(error.to_string()),
+                                range: block.range.clone(),
+                            });
+                            None
+                        }
+                    },
+                    false => None,
+                };
+
+                if block.is_diagram() {
+                    let mut code = block.code.clone();
+
+                    if let Some(init) = &parse_opts.mermaid_init {
+                        if block.fence.language == "mermaid"
+                            && !code.trim_start().starts_with("%%{init")
+                        {
+                            code.insert_str(0, &format!("%%{{init: {}}}%%\n", init));
+                        }
+                    }
+
+                    events.push(Event::Text(code.into()));
+                    events.push(Event::Html(CowStr::Borrowed(match block.diagram_class {
+                        Some(_) => "</pre>\n",
+                        None => "</div>\n",
+                    })));
+                } else if let Some(table) = table {
+                    events.push(Event::Html(table.into()));
                 } else {
-                    events.push(Event::End(Tag::CodeBlock(CodeBlockKind::Fenced(inner))));
+                    if block.is_table() {
+                        let opening = code_block_opening(&block, &parse_opts);
+                        if !opening.is_empty() {
+                            events.push(Event::Html(opening.into()));
+                        }
+
+                        events.push(code_block_start(&block, kind.clone()));
+                    }
+
+                    let closing = code_block_closing(&block, &parse_opts);
+
+                    events.push(code_block_contents(block, &parse_opts));
+                    events.push(code_block_end(kind));
+
+                    if !closing.is_empty() {
+                        events.push(Event::Html(closing.into()));
+                    }
                 }
+
+                if block_tab {
+                    events.push(Event::Html(CowStr::Borrowed("</div>\n")));
+                }
+            }
+            // Footnotes get ids of their own, so they can't clash with the
+            // ids of headings, and so the sanitizer can tell them apart.
+            Event::FootnoteReference(name) => {
+                footnote_references.push((events.len(), name.to_string(), range));
+                events.push(Event::Html(CowStr::Borrowed("")));
+            }
+            Event::Start(Tag::FootnoteDefinition(name)) => {
+                footnote_starts.push((events.len(), name.to_string()));
+            }
+            // Definitions are moved to the end of the document.
+            Event::End(Tag::FootnoteDefinition(_)) => {
+                let (start, name) = footnote_starts.pop().unwrap();
+                let definition: Vec<Event> = events.drain(start..).collect();
+
+                let inner = footnote_references
+                    .iter()
+                    .position(|(position, _, _)| *position >= start)
+                    .unwrap_or(footnote_references.len());
+                let mut references = footnote_references.split_off(inner);
+                for (position, _, _) in &mut references {
+                    *position -= start;
+                }
+
+                footnote_definitions
+                    .entry(name)
+                    .or_insert((definition, references));
+                last_image = None;
+            }
+
+            Event::Html(ref html) if !parse_opts.id_elements.is_empty() => {
+                for id in html_element_ids(html, &parse_opts.id_elements) {
+                    element_ids.push((id, range.clone()));
+                }
+
+                events.push(event);
             }
+
             Event::Code(ref text) => {
                 if let Some(heading) = &mut current_heading {
                     if heading.anchor.len() != 0 {
@@ -173,56 +1460,205 @@ pub fn parse(input: &str, opts: Option<ParseOptions>) -> Markdown {
 
                     heading.title.push_str(&text);
                 }
-                events.push(event);
+
+                match &parse_opts.inline_code_class {
+                    // Images still need the code for their alt text.
+                    Some(class) if current_image.is_none() => {
+                        let mut html = String::from("<code class=\"");
+                        escape_html(&mut html, class).unwrap();
+                        html.push_str("\">");
+                        escape_html(&mut html, text).unwrap();
+                        html.push_str("</code>");
+                        events.push(Event::Html(html.into()));
+                    }
+                    _ => events.push(event),
+                }
             }
 
-            // Link rewrites
+            // Link rewrites. Reference-style links arrive here already resolved
+            // to their definition, so they get the same treatment as inline links.
             Event::Start(Tag::Link(link_type, url, title)) => {
-                let (link_type, url, title) = rewrite_link(link_type, url, title, &parse_opts);
+                let original = url.to_string();
+                let (link_type, url, title) =
+                    rewrite_link(link_type, url, title, LinkKind::Link, &parse_opts);
 
-                let url = if !parse_opts.url_params.is_empty() && is_in_local_domain(&url) {
+                let url = if link_type != LinkType::Email && accepts_parameters(&url, &parse_opts) {
                     append_parameters(url, &parse_opts)
                 } else {
                     url
                 };
 
-                if link_type == LinkType::Inline {
-                    if let Ok(valid_url) = Url::parse(&url.clone())
-                        .map(|u| UrlType::Remote(u))
-                        .or_else(|e| match e {
-                            ParseError::EmptyHost | ParseError::RelativeUrlWithoutBase => {
-                                Ok(UrlType::Local(PathBuf::from(url.clone().into_string())))
-                            }
-                            e => Err(e),
-                        })
-                        .map_err(|l| l)
-                    {
-                        current_link = Some(Link {
-                            title: title.clone().to_string(),
-                            url: valid_url,
-                        });
-                    }
-                }
-                events.push(Event::Start(Tag::Link(link_type, url, title)));
-            }
+                let url = if link_type != LinkType::Email {
+                    absolute_url(url, &parse_opts)
+                } else {
+                    url
+                };
 
-            Event::End(Tag::Link(link_type, url, title)) => {
-                if current_link.is_some() {
-                    links.push(current_link.take().unwrap())
-                }
+                let url_type = classify_url(&url, link_type);
+                // Only links to other sites count as external, not e.g. `tel:` links.
+                let is_external = matches!(
+                    &url_type,
+                    Some(UrlType::Remote(url)) if url.has_host() && !is_on_base_url(url, &parse_opts)
+                );
+                pending_link_icon = parse_opts.external_link_icon.is_some() && is_external;
 
-                events.push(Event::End(Tag::Link(link_type, url, title)));
-            }
+                let external_attributes = if is_external {
+                    external_link_attributes(&parse_opts)
+                } else {
+                    Vec::new()
+                };
+
+                link_start = Some((events.len(), url.to_string()));
+
+                if !external_attributes.is_empty() {
+                    events.push(Event::Html(
+                        link_start_html(&url, &title, &external_attributes).into(),
+                    ));
+                } else {
+                    events.push(Event::Start(Tag::Link(link_type, url, title.clone())));
+                }
+
+                if let Some(valid_url) = url_type {
+                    current_link = Some(Link {
+                        title: String::new(),
+                        url: valid_url,
+                        kind: link_type.into(),
+                        range,
+                        section_anchor: headings.last().map(|heading| heading.anchor.clone()),
+                        original,
+                    });
+                }
+            }
+
+            Event::End(Tag::Link(link_type, url, title)) => {
+                // Links without any content show their URL instead.
+                if let Some((start_index, href)) = link_start.take() {
+                    if events.len() == start_index + 1 {
+                        if let Some(link) = &mut current_link {
+                            link.title = href.clone();
+                        }
+
+                        events.push(Event::Text(href.into()));
+                    }
+                }
+
+                if current_link.is_some() {
+                    links.push(current_link.take().unwrap())
+                }
+
+                if pending_link_icon {
+                    if let Some(icon) = &parse_opts.external_link_icon {
+                        events.push(Event::Html(icon.as_str().into()));
+                    }
+                    pending_link_icon = false;
+                }
+
+                events.push(Event::End(Tag::Link(link_type, url, title)));
+            }
 
             // Image link rewrites
             Event::Start(Tag::Image(link_type, url, title)) => {
-                let (link_type, url, title) = rewrite_link(link_type, url, title, &parse_opts);
+                // Links wrapping an image don't get the external link icon.
+                pending_link_icon = false;
+
+                let original = url.to_string();
+                let (link_type, url, title) = rewrite_image(link_type, url, title, &parse_opts);
+                let size = image_sizes.get(&range.start).copied();
+
+                if let Some(src) = classify_url(&url, link_type) {
+                    current_image = Some(Image {
+                        src,
+                        alt: String::new(),
+                        title: Some(title.to_string()).filter(|title| !title.is_empty()),
+                        range,
+                        original,
+                        width: size.map(|size| size.width),
+                        height: size.and_then(|size| size.height),
+                        link: current_link.as_ref().map(|link| link.url.clone()),
+                    });
+                }
+
+                let mut attributes = Vec::new();
+
+                if let Some(size) = size {
+                    attributes.push(("width", size.width.to_string()));
+                    if let Some(height) = size.height {
+                        attributes.push(("height", height.to_string()));
+                    }
+                }
+
+                if let Some(variants) = parse_opts
+                    .hooks
+                    .image_resolver
+                    .as_ref()
+                    .and_then(|resolver| resolver.resolve(&url))
+                {
+                    if !variants.srcset.is_empty() {
+                        let srcset = variants
+                            .srcset
+                            .iter()
+                            .map(|(url, descriptor)| format!("{} {}", url, descriptor))
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        attributes.push(("srcset", srcset));
+                    }
+                    if let Some(sizes) = variants.sizes {
+                        attributes.push(("sizes", sizes));
+                    }
+                }
+
+                let zoomable = current_link.is_none()
+                    && match parse_opts.zoomable_images {
+                        ZoomableImages::Never => false,
+                        ZoomableImages::Always => true,
+                        ZoomableImages::WiderThan(min_width) => {
+                            is_in_local_domain(&url)
+                                && size.is_some_and(|size| size.width > min_width)
+                        }
+                    };
+
+                if zoomable {
+                    attributes.push(("data-zoomable", String::new()));
+                }
+
+                image_start = Some((events.len(), attributes));
 
                 events.push(Event::Start(Tag::Image(link_type, url, title)));
             }
 
+            Event::End(Tag::Image(..)) => {
+                if let Some(image) = current_image.take() {
+                    images.push(image);
+                }
+
+                let (start_index, attributes) = image_start.take().unwrap();
+
+                let title = match &events[start_index] {
+                    Event::Start(Tag::Image(_, _, title)) => title.to_string(),
+                    _ => String::new(),
+                };
+
+                // The HTML writer has no way to add attributes to an image, so
+                // such images are written out by hand.
+                if !attributes.is_empty() {
+                    let mut html = image_html(&events[start_index..], &attributes);
+
+                    if attributes.iter().any(|(name, _)| *name == "data-zoomable") {
+                        html = zoom_link_html(&events[start_index], html);
+                    }
+
+                    events.truncate(start_index);
+                    events.push(Event::Html(html.into()));
+                } else {
+                    events.push(event);
+                }
+
+                last_image = Some((start_index..events.len(), title));
+            }
+
             // Apply heading anchor tags
             Event::Start(Tag::Heading(level @ 1..=6)) => {
+                heading_links_start = links.len();
                 current_heading = Some(Heading {
                     level: level,
                     anchor: String::new(),
@@ -233,7 +1669,14 @@ pub fn parse(input: &str, opts: Option<ParseOptions>) -> Markdown {
             }
 
             Event::End(Tag::Heading(_)) => {
-                let closed_heading = current_heading.take().unwrap();
+                let mut closed_heading = current_heading.take().unwrap();
+                closed_heading
+                    .title
+                    .truncate(closed_heading.title.trim_end().len());
+
+                for link in &mut links[heading_links_start..] {
+                    link.section_anchor = Some(closed_heading.anchor.clone());
+                }
 
                 let header_start = events
                     .iter_mut()
@@ -249,12 +1692,97 @@ pub fn parse(input: &str, opts: Option<ParseOptions>) -> Markdown {
                     closed_heading.level, closed_heading.anchor
                 )));
 
-                headings.push(closed_heading);
+                if open_details > 0 && parse_opts.exclude_collapsible_headings {
+                    element_ids.push((closed_heading.anchor, range));
+                } else {
+                    headings.push(closed_heading);
+                }
+                events.push(event);
+            }
+
+            Event::Rule if container_fences.contains_key(&range.start) => {
+                let html = match &container_fences[&range.start] {
+                    ContainerFence::Open {
+                        kind: ContainerKind::Admonition(kind),
+                        title,
+                    } => admonition_start_html(kind, title.as_deref(), &parse_opts),
+                    ContainerFence::Open {
+                        kind: ContainerKind::Details { open },
+                        title,
+                    } => {
+                        open_details += 1;
+                        details_start_html(*open, title.as_deref())
+                    }
+                    ContainerFence::Close(ContainerKind::Admonition(_)) => "</div>\n".to_string(),
+                    ContainerFence::Close(ContainerKind::Details { .. }) => {
+                        open_details -= 1;
+                        "</details>\n".to_string()
+                    }
+                };
+
+                events.push(Event::Html(html.into()));
+            }
+
+            Event::Start(Tag::List(None)) if definition_lines.contains(&range.start) => {
+                open_lists.push(true);
+
+                // The paragraph right before the definitions holds the
+                // terms, one per line.
+                let terms = match events.last() {
+                    Some(Event::End(Tag::Paragraph)) => events
+                        .iter()
+                        .rposition(|event| matches!(event, Event::Start(Tag::Paragraph))),
+                    _ => None,
+                };
+
+                if let Some(start) = terms {
+                    let end = events.len() - 1;
+                    // Terms right after another definition list extend it.
+                    let continues = start > 0
+                        && matches!(&events[start - 1], Event::Html(html) if html.as_ref() == "</dl>\n");
+
+                    if continues {
+                        events[start - 1] = Event::Html("".into());
+                        events[start] = Event::Html("<dt>".into());
+                    } else {
+                        events[start] = Event::Html("<dl>\n<dt>".into());
+                    }
+
+                    for event in &mut events[start + 1..end] {
+                        if matches!(event, Event::SoftBreak) {
+                            *event = Event::Html("</dt>\n<dt>".into());
+                        }
+                    }
+
+                    events[end] = Event::Html("</dt>\n".into());
+                } else {
+                    events.push(Event::Html("<dl>\n".into()));
+                }
+            }
+
+            Event::Start(Tag::List(_)) => {
+                open_lists.push(false);
                 events.push(event);
             }
 
+            Event::End(Tag::List(_)) => {
+                if open_lists.pop() == Some(true) {
+                    events.push(Event::Html("</dl>\n".into()));
+                } else {
+                    events.push(event);
+                }
+            }
+
+            Event::Start(Tag::Item) if open_lists.last() == Some(&true) => {
+                events.push(Event::Html("<dd>".into()));
+            }
+
+            Event::End(Tag::Item) if open_lists.last() == Some(&true) => {
+                events.push(Event::Html("</dd>\n".into()));
+            }
+
             Event::Start(Tag::Paragraph) => {
-                if let Some(next_event) = parser.peek() {
+                if let Some((next_event, _)) = parser.peek() {
                     match next_event {
                         Event::Text(text) => {
                             if !is_callout_start(&text) && !is_callout_end(&text) {
@@ -269,7 +1797,32 @@ pub fn parse(input: &str, opts: Option<ParseOptions>) -> Markdown {
             }
 
             Event::End(Tag::Paragraph) => {
-                if let Some(Event::Start(Tag::Paragraph)) = events.last() {
+                // An image with a title that is alone in its paragraph becomes a
+                // figure, with the title as its caption.
+                let figure_caption = match &last_image {
+                    Some((image_events, title))
+                        if parse_opts.image_figures
+                            && !title.is_empty()
+                            && image_events.end == events.len()
+                            && image_events.start > 0
+                            && matches!(
+                                events[image_events.start - 1],
+                                Event::Start(Tag::Paragraph)
+                            ) =>
+                    {
+                        Some((image_events.start - 1, title.clone()))
+                    }
+                    _ => None,
+                };
+
+                if let Some((paragraph_start, caption)) = figure_caption {
+                    events[paragraph_start] = Event::Html("<figure>".into());
+
+                    let mut html = String::from("<figcaption>");
+                    escape_html(&mut html, &caption).unwrap();
+                    html.push_str("</figcaption></figure>\n");
+                    events.push(Event::Html(html.into()));
+                } else if let Some(Event::Start(Tag::Paragraph)) = events.last() {
                     events.pop();
                 } else if is_callout_close_event(events.last()) {
                     // Skip
@@ -279,23 +1832,61 @@ pub fn parse(input: &str, opts: Option<ParseOptions>) -> Markdown {
             }
 
             Event::Text(text) => {
-                let text = convert_emojis(&text);
+                // Most text has no emoji, and can be passed on as it is.
+                let mut unknown_emojis = Vec::new();
+                let converted = match convert_emojis(&text, &parse_opts, &mut unknown_emojis) {
+                    Cow::Owned(converted) => Some(converted),
+                    Cow::Borrowed(_) => None,
+                };
+
+                for (emoji_range, identifier) in unknown_emojis {
+                    warnings.push(Warning {
+                        kind: WarningKind::UnknownEmoji(identifier),
+                        range: (range.start + emoji_range.start).min(range.end)
+                            ..(range.start + emoji_range.end).min(range.end),
+                    });
+                }
+                let text = converted.map(CowStr::from).unwrap_or(text);
 
                 if let Some(link) = &mut current_link {
                     // We are in the middle of parsing a link. Push the title.
                     link.title.push_str(&text);
                 }
 
+                if let Some(image) = &mut current_image {
+                    image.alt.push_str(&text);
+                }
+
                 if let Some(heading) = &mut current_heading {
                     if heading.anchor.len() != 0 {
                         heading.anchor.push('-');
                     }
 
+                    let anchor_text = match parse_opts.smart_punctuation {
+                        true => unsmarten(&text),
+                        false => Cow::Borrowed(text.as_ref()),
+                    };
+
                     heading
                         .anchor
-                        .push_str(&text.clone().trim().to_lowercase().replace(" ", "-"));
+                        .push_str(&anchor_text.trim().to_lowercase().replace(" ", "-"));
 
-                    heading.title.push_str(&text);
+                    // Image emojis are left out of titles, so the spaces on
+                    // either side of them would double up.
+                    let after_image_emoji = matches!(
+                        events.last(),
+                        Some(Event::Html(html)) if html.starts_with("<img class=\"custom-emoji\"")
+                    );
+
+                    let title_text = if heading.title.is_empty()
+                        || (after_image_emoji && heading.title.ends_with(char::is_whitespace))
+                    {
+                        text.trim_start()
+                    } else {
+                        &text
+                    };
+
+                    heading.title.push_str(title_text);
                 }
 
                 if active_callout.is_some() && is_callout_end(&text) {
@@ -304,7 +1895,7 @@ pub fn parse(input: &str, opts: Option<ParseOptions>) -> Markdown {
                         events.push(Event::End(Tag::Paragraph));
                     }
                     events.push(Event::Html(CowStr::from(format!("</div>"))));
-                    if Some(&Event::SoftBreak) == parser.peek() {
+                    if matches!(parser.peek(), Some((Event::SoftBreak, _))) {
                         events.push(Event::Start(Tag::Paragraph));
                     }
                 } else if active_callout.is_none() && is_callout_start(&text) {
@@ -323,26 +1914,101 @@ pub fn parse(input: &str, opts: Option<ParseOptions>) -> Markdown {
                             ))));
                         }
                     } else {
-                        events.push(Event::Text(text.into()));
+                        events.push(Event::Text(text));
                     }
 
                     events.push(Event::Start(Tag::Paragraph));
+                } else if let (Some(abbreviations), None) = (&abbreviations, &current_image) {
+                    events.extend(abbreviations.wrap(text));
                 } else {
-                    events.push(Event::Text(text.into()));
+                    events.push(Event::Text(text));
                 }
             }
             _ => events.push(event),
         };
     }
 
+    if let Some(group) = tab_group.take() {
+        close_tab_group(&mut events, group);
+    }
+
+    let mut footnote_ids = Vec::new();
+
+    if !footnote_references.is_empty() {
+        let defined: HashSet<String> = footnote_definitions.keys().cloned().collect();
+        let mut numbered: Vec<String> = Vec::new();
+
+        resolve_footnote_references(
+            &mut events,
+            footnote_references,
+            &defined,
+            &mut numbered,
+            &mut warnings,
+        );
+
+        if !numbered.is_empty() {
+            events.push(Event::Html(CowStr::Borrowed(
+                "<section class=\"footnotes\">\n<ol>\n",
+            )));
+
+            // Definitions can reference other footnotes, which are numbered
+            // after the ones referenced so far.
+            let mut index = 0;
+            while index < numbered.len() {
+                let name = numbered[index].clone();
+                index += 1;
+
+                let (mut definition, references) = footnote_definitions.remove(&name).unwrap();
+                resolve_footnote_references(
+                    &mut definition,
+                    references,
+                    &defined,
+                    &mut numbered,
+                    &mut warnings,
+                );
+
+                let mut item = String::from("<li id=\"");
+                escape_html(&mut item, &footnote_id(&name)).unwrap();
+                item.push_str("\">\n");
+                events.push(Event::Html(item.into()));
+
+                let mut back_link = String::from("<a href=\"#");
+                escape_href(&mut back_link, &footnote_reference_id(&name)).unwrap();
+                back_link.push_str("\" class=\"footnote-backref\">↩</a>");
+
+                // The link back goes at the end of the last paragraph, if
+                // the definition ends in one.
+                if let Some(Event::End(Tag::Paragraph)) = definition.last() {
+                    let back_link = format!(" {}", back_link);
+                    definition.insert(definition.len() - 1, Event::Html(back_link.into()));
+                } else {
+                    definition.push(Event::Html(back_link.into()));
+                }
+
+                events.extend(definition);
+                events.push(Event::Html(CowStr::Borrowed("</li>\n")));
+
+                footnote_ids.push(footnote_id(&name));
+                footnote_ids.push(footnote_reference_id(&name));
+            }
+
+            events.push(Event::Html(CowStr::Borrowed("</ol>\n</section>\n")));
+        }
+    }
+
     // Write to String buffer.
     let mut as_html = String::new();
     html::push_html(&mut as_html, events.into_iter());
 
     let mut allowed_div_classes = HashSet::new();
     // Mermaid JS and math blocks
-    allowed_div_classes.insert("mermaid");
     allowed_div_classes.insert("math");
+    // Code block titles
+    allowed_div_classes.insert("code-block");
+    allowed_div_classes.insert("code-title");
+    allowed_div_classes.insert("code-tabs");
+    allowed_div_classes.insert("code-tab-labels");
+    allowed_div_classes.insert("code-tab-panel");
     // Callout-specific
     allowed_div_classes.insert("callout");
     allowed_div_classes.insert("callout-title");
@@ -351,10 +2017,153 @@ pub fn parse(input: &str, opts: Option<ParseOptions>) -> Markdown {
     allowed_div_classes.insert("warning");
     allowed_div_classes.insert("error");
 
+    if let Some(class) = &parse_opts.code_container_class {
+        allowed_div_classes.insert(class.as_str());
+    }
+
+    if parse_opts.admonitions {
+        allowed_div_classes.insert("admonition");
+        allowed_div_classes.extend(parse_opts.admonition_types.iter().map(String::as_str));
+    }
+
     let mut allowed_classes = HashMap::new();
     allowed_classes.insert("div", allowed_div_classes);
+    if !parse_opts.diagram_languages.is_empty() {
+        allowed_classes.insert(
+            "pre",
+            parse_opts
+                .diagram_languages
+                .values()
+                .map(String::as_str)
+                .collect(),
+        );
+    }
+
+    let mut span_classes = HashSet::new();
+    // Numbered and highlighted code lines
+    span_classes.insert("line".to_string());
+    span_classes.insert("hl-line".to_string());
+    // Diff lines
+    span_classes.insert("diff-add".to_string());
+    span_classes.insert("diff-del".to_string());
+    span_classes.insert("diff-hunk".to_string());
+    span_classes.insert("diff-header".to_string());
+    // Shell sessions
+    span_classes.insert("prompt".to_string());
+    span_classes.insert("output".to_string());
+    // Code tab labels
+    span_classes.insert("code-tab-label".to_string());
+
+    if let Some(icon) = &parse_opts.external_link_icon {
+        for captures in SNIPPET_CLASSES.captures_iter(icon) {
+            if let Some(classes) = captures.get(1) {
+                span_classes.extend(classes.as_str().split_whitespace().map(String::from));
+            }
+        }
+    }
+
+    if let Some(classes) = parse_opts.extra_allowed_classes.get("span") {
+        span_classes.extend(classes.iter().cloned());
+    }
+
+    // Highlighted code uses an open-ended set of span classes, which are
+    // checked by the attribute policy instead.
+    let span_class_prefix = highlight_class_prefix(&parse_opts).map(String::from);
+
+    if span_class_prefix.is_none() && !span_classes.is_empty() {
+        allowed_classes.insert(
+            "span",
+            span_classes
+                .iter()
+                .map(String::as_str)
+                .collect::<HashSet<_>>(),
+        );
+    }
+
+    if let Some(class) = &parse_opts.external_link_class {
+        allowed_classes
+            .entry("a")
+            .or_insert_with(HashSet::new)
+            .insert(class.as_str());
+    }
+
+    if parse_opts
+        .custom_emoji
+        .values()
+        .any(|emoji| matches!(emoji, CustomEmoji::Image(_)))
+    {
+        allowed_classes.insert("img", ["custom-emoji"].iter().copied().collect());
+    }
+
+    if parse_opts.footnotes {
+        allowed_classes.insert("sup", ["footnote-reference"].iter().copied().collect());
+        allowed_classes.insert("section", ["footnotes"].iter().copied().collect());
+        allowed_classes
+            .entry("a")
+            .or_insert_with(HashSet::new)
+            .insert("footnote-backref");
+    }
+
+    if parse_opts.zoomable_images != ZoomableImages::Never {
+        allowed_classes
+            .entry("a")
+            .or_insert_with(HashSet::new)
+            .insert("zoom");
+    }
+
+    // Tags that allow any class can't also have a list of allowed ones.
+    let any_class_tags: HashSet<&str> = ["code", "p", "mark"]
+        .iter()
+        .copied()
+        .chain(
+            parse_opts
+                .extra_tag_attributes
+                .iter()
+                .filter(|(_, attributes)| attributes.iter().any(|a| a == "class"))
+                .map(|(tag, _)| tag.as_str()),
+        )
+        .collect();
+
+    for (tag, classes) in &parse_opts.extra_allowed_classes {
+        if tag != "span" && !any_class_tags.contains(tag.as_str()) {
+            allowed_classes
+                .entry(tag.as_str())
+                .or_insert_with(HashSet::new)
+                .extend(classes.iter().map(String::as_str));
+        }
+    }
+
+    let custom_elements: Vec<(&str, &CustomElement)> = parse_opts
+        .custom_elements
+        .iter()
+        .filter(|(name, _)| is_custom_element_name(name))
+        .map(|(name, element)| (name.as_str(), element))
+        .collect();
+
+    for (name, element) in &custom_elements {
+        if !element.classes.is_empty() {
+            allowed_classes
+                .entry(name)
+                .or_insert_with(HashSet::new)
+                .extend(element.classes.iter().map(String::as_str));
+        }
+    }
+
+    for tag in &any_class_tags {
+        allowed_classes.remove(tag);
+    }
+
+    let external_rel = external_link_rel(&parse_opts);
 
-    let safe_html = ammonia::Builder::new()
+    let url_schemes: Vec<String> = parse_opts
+        .url_schemes
+        .iter()
+        .map(|scheme| scheme.to_ascii_lowercase())
+        .filter(|scheme| !matches!(scheme.as_str(), "javascript" | "vbscript" | "data"))
+        .collect();
+
+    let mut builder = ammonia::Builder::new();
+    builder
         .link_rel(None)
         .add_tags(&["h1"])
         .add_tag_attributes("h1", &["id"])
@@ -372,597 +2181,7455 @@ pub fn parse(input: &str, opts: Option<ParseOptions>) -> Markdown {
         .add_tag_attributes("code", &["class"])
         .add_tags(&["p"])
         .add_tag_attributes("p", &["class"])
+        .add_tags(&["kbd", "sup", "sub", "mark"])
+        // Table column alignment, limited to the values pulldown writes
+        .rm_tag_attributes("th", &["align"])
+        .add_tag_attribute_values("th", "align", &["left", "center", "right"])
+        .rm_tag_attributes("td", &["align"])
+        .add_tag_attribute_values("td", "align", &["left", "center", "right"])
+        .add_tag_attributes("mark", &["class"])
+        .add_tag_attributes("img", &["width", "height", "alt"])
+        .add_tag_attributes("span", &["data-line", "data-tab"])
+        .add_tag_attributes("div", &["data-tab"])
         .add_tags(&["input"])
         .add_tag_attribute_values("input", "disabled", &[""])
         .add_tag_attribute_values("input", "type", &["checkbox"])
         .add_tag_attribute_values("input", "checked", &[""])
+        // Task list checkboxes are the only inputs, so nothing else can be
+        // typed into.
+        .set_tag_attribute_value("input", "type", "checkbox")
         .allowed_classes(allowed_classes)
-        .add_clean_content_tags(&["form", "script", "style"])
-        .clean(&*as_html)
-        .to_string();
+        .add_clean_content_tags(&["form", "script", "style"]);
 
-    Markdown {
-        as_html: safe_html,
-        links,
-        headings,
+    if parse_opts.image_figures {
+        builder.add_tags(&["figure", "figcaption"]);
     }
-}
 
-/// Rewrites the link by either setting a different root path, or by
-/// swapping the whole URL if there is a matching rule in the rewrite
-/// rules.
-fn rewrite_link<'a>(
-    link_type: LinkType,
-    url: CowStr<'a>,
-    title: CowStr<'a>,
-    parse_opts: &'a ParseOptions,
-) -> (LinkType, CowStr<'a>, CowStr<'a>) {
-    if let Some(matching_link) = parse_opts
-        .link_rewrite_rules
-        .get(&url.clone().into_string())
-    {
-        (link_type, matching_link.as_str().into(), title)
-    } else if Path::new(&url.clone().into_string()).starts_with("/") {
-        let mut rewritten = parse_opts.url_root.trim_end_matches('/').to_string();
-        rewritten.push_str(&url.to_string());
+    if parse_opts.definition_lists {
+        builder.add_tags(&["dl", "dt", "dd"]);
+    }
+
+    if parse_opts.abbreviations {
+        builder
+            .add_tags(&["abbr"])
+            .add_tag_attributes("abbr", &["title"]);
+    }
+
+    if parse_opts.html_comments != HtmlComments::Remove {
+        builder.strip_comments(false);
+    }
+
+    // Only footnote ids are let through, see `AttributePolicy`.
+    if parse_opts.footnotes {
+        builder
+            .add_tags(&["section"])
+            .add_tag_attributes("li", &["id"])
+            .add_tag_attributes("sup", &["id"]);
+    }
+
+    for tag in &parse_opts.id_elements {
+        builder.add_tag_attributes(tag.as_str(), &["id"]);
+    }
 
-        (link_type, rewritten.into(), title)
+    if parse_opts.allow_details {
+        builder.add_tag_attribute_values("details", "open", &[""]);
     } else {
-        (link_type, url, title)
+        builder.rm_tags(&["details", "summary"]);
     }
-}
 
-fn append_parameters<'a>(url: CowStr<'a>, parse_opts: &'a ParseOptions) -> CowStr<'a> {
-    let mut appended = url.into_string();
-    appended.push_str("?");
+    if !parse_opts.allowed_iframe_hosts.is_empty() {
+        builder.add_tags(&["iframe"]).add_tag_attributes(
+            "iframe",
+            &["src", "width", "height", "allow", "allowfullscreen"],
+        );
+    }
 
-    let mut position = 0;
-    let length = parse_opts.url_params.len();
+    if parse_opts.media_elements {
+        builder
+            .add_tags(&["video", "audio", "source", "track"])
+            .add_tag_attributes(
+                "video",
+                &["src", "controls", "poster", "loop", "muted", "preload"],
+            )
+            .add_tag_attributes("audio", &["src", "controls", "loop", "muted", "preload"])
+            .add_tag_attributes("source", &["src", "type"])
+            .add_tag_attributes("track", &["src", "kind", "srclang", "label"]);
+    }
 
-    for (key, value) in &parse_opts.url_params {
-        appended.push_str(key);
-        appended.push_str("=");
-        appended.push_str(value);
+    if parse_opts.picture_elements {
+        builder
+            .add_tags(&["picture", "source"])
+            .add_tag_attributes("source", &["srcset", "media", "type"]);
+    }
 
-        position += 1;
-        if position != length {
-            appended.push_str("&");
+    // Attributes given by prefix are allowed on every tag by the sanitizer,
+    // and taken off the other tags by the attribute policy.
+    let mut custom_attribute_prefixes: HashMap<String, Vec<String>> = HashMap::new();
+
+    for (name, element) in &custom_elements {
+        builder.add_tags(std::iter::once(*name));
+
+        for attribute in &element.attributes {
+            match attribute.strip_suffix('*') {
+                Some(prefix) if is_safe_custom_attribute_prefix(prefix) => {
+                    builder.add_generic_attribute_prefixes(std::iter::once(prefix));
+                    custom_attribute_prefixes
+                        .entry(name.to_string())
+                        .or_default()
+                        .push(prefix.to_string());
+                }
+                Some(_) => {}
+                None if is_safe_custom_attribute(attribute) => {
+                    builder.add_tag_attributes(*name, std::iter::once(attribute.as_str()));
+                }
+                None => {}
+            }
         }
     }
 
-    appended.into()
-}
+    // Tags whose contents are always removed can't be allowed.
+    builder.add_tags(
+        parse_opts
+            .extra_allowed_tags
+            .iter()
+            .map(String::as_str)
+            .filter(|tag| !matches!(*tag, "form" | "script" | "style")),
+    );
+
+    for (tag, attributes) in &parse_opts.extra_tag_attributes {
+        builder.add_tag_attributes(tag.as_str(), attributes.iter().map(String::as_str));
+    }
 
-fn is_in_local_domain(url_string: &str) -> bool {
-    match Url::parse(url_string) {
-        Ok(url) => url.host().is_none(),
-        Err(url::ParseError::RelativeUrlWithoutBase) => true,
-        Err(url::ParseError::EmptyHost) => true,
-        Err(_) => false,
+    if parse_opts.zoomable_images != ZoomableImages::Never {
+        builder.add_tag_attribute_values("img", "data-zoomable", &[""]);
     }
-}
 
-lazy_static! {
-    static ref CALLOUT_PATTERN_START: Regex =
-        Regex::new(r"^\{%\s*(?P<type>\w+)\s*(?P<title>.*)\s*%\}$").unwrap();
-    static ref CALLOUT_PATTERN_END: Regex = Regex::new(r"\{%\s*end\s*%\}").unwrap();
-}
+    if parse_opts.code_container_class.is_some() {
+        builder.add_tag_attributes("div", &["data-lang"]);
+    }
 
-fn is_callout_start(text: &str) -> bool {
-    parse_callout(text).is_some()
-}
+    builder.url_schemes(url_schemes.iter().map(String::as_str).collect());
 
-fn is_callout_end(text: &str) -> bool {
-    CALLOUT_PATTERN_END.is_match(text)
-}
+    if parse_opts.allow_data_image_urls {
+        builder.add_url_schemes(&["data"]);
+    }
 
-fn is_callout_close_event(event: Option<&Event>) -> bool {
-    event == Some(&Event::Html(CowStr::from(format!("</div>"))))
-}
+    if parse_opts.hooks.image_resolver.is_some() {
+        builder.add_tag_attributes("img", &["srcset", "sizes"]);
+    }
 
-fn parse_callout(text: &str) -> Option<Callout> {
-    if let Some(captures) = CALLOUT_PATTERN_START.captures(&text.trim_end()) {
-        match (captures.name("type"), captures.name("title")) {
-            (Some(callout_type), None) => Callout::build(callout_type.as_str()).ok(),
-            (Some(callout_type), Some(title)) => {
-                Callout::build_with_title(callout_type.as_str(), title.as_str().trim()).ok()
-            }
-            _ => None,
-        }
-    } else {
-        None
+    if span_class_prefix.is_some() {
+        builder.add_tag_attributes("span", &["class"]);
     }
-}
 
-fn convert_emojis(input: &str) -> String {
-    let mut acc = String::with_capacity(input.len());
-    let mut parsing_emoji = false;
-    let mut emoji_identifier = String::new();
+    if highlight_uses_inline_styles(&parse_opts) {
+        builder.add_tag_attributes("span", &["style"]);
+    }
 
-    for c in input.chars() {
-        match (c, parsing_emoji) {
-            (':', false) => parsing_emoji = true,
-            (':', true) => {
-                if let Some(emoji) = emojis::lookup(&emoji_identifier) {
-                    acc.push_str(emoji.as_str());
-                } else {
-                    acc.push(':');
-                    acc.push_str(&emoji_identifier);
-                    acc.push(':');
-                }
+    if parse_opts.external_links_in_new_tab {
+        builder.add_tag_attribute_values("a", "target", &["_blank"]);
+    }
 
-                parsing_emoji = false;
-                emoji_identifier.truncate(0);
-            }
-            (_, true) => emoji_identifier.push(c),
-            (_, false) => acc.push(c),
-        }
+    if parse_opts.external_link_icon.is_some() {
+        builder.add_tag_attribute_values("span", "aria-hidden", &["true"]);
     }
 
-    if parsing_emoji {
-        acc.push(':');
-        acc.push_str(&emoji_identifier);
+    if let Some(rel) = &external_rel {
+        builder.add_tag_attribute_values("a", "rel", std::iter::once(rel));
     }
 
-    acc
-}
+    let attribute_policy = AttributePolicy {
+        allow_data_image_urls: parse_opts.allow_data_image_urls,
+        span_class_prefix,
+        span_classes: span_classes.clone(),
+        iframe_hosts: parse_opts.allowed_iframe_hosts.clone(),
+        footnote_ids: Some(footnote_ids.iter().cloned().collect()).filter(|_| parse_opts.footnotes),
+        id_elements: parse_opts.id_elements.iter().cloned().collect(),
+        media_hosts: parse_opts.allowed_media_hosts.clone(),
+        media_options: Some(parse_opts.clone()).filter(|_| parse_opts.media_elements),
+        explicit_attributes: if custom_attribute_prefixes.is_empty() {
+            HashMap::new()
+        } else {
+            explicit_attributes(&builder)
+        },
+        custom_attribute_prefixes,
+    };
+    let sanitizer_hook = parse_opts.hooks.sanitizer.clone();
+    builder.attribute_filter(move |element, attribute, value| {
+        let value = attribute_policy.filter(element, attribute, value)?;
+
+        match &sanitizer_hook {
+            Some(hook) => match value {
+                Cow::Borrowed(value) => hook.filter_attribute(element, attribute, value),
+                Cow::Owned(value) => hook
+                    .filter_attribute(element, attribute, &value)
+                    .map(|value| Cow::Owned(value.into_owned())),
+            },
+            None => Some(value),
+        }
+    });
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    if let Some(hook) = &parse_opts.hooks.sanitizer {
+        hook.configure(&mut builder);
+    }
 
-    #[test]
-    fn parses_a_markdown_doc() {
-        let input = indoc! {"
-        # My heading
+    let raw_html = parse_opts.keep_raw_html.then(|| as_html.clone());
+
+    let safe_html = if parse_opts.sanitize {
+        let clean = builder.clean(&as_html).to_string();
+
+        // Iframes from other hosts have lost their `src` to the attribute
+        // policy, and are removed altogether.
+        let clean = if parse_opts.allowed_iframe_hosts.is_empty() {
+            clean
+        } else {
+            remove_iframes_without_src(&clean).into_owned()
+        };
+
+        match &parse_opts.html_comments {
+            HtmlComments::KeepWithPrefix(prefix) => {
+                remove_comments_without_prefix(&clean, prefix).into_owned()
+            }
+            _ => clean,
+        }
+    } else {
+        as_html
+    };
+
+    let broken_links = match &parse_opts.valid_paths {
+        Some(valid_paths) => links
+            .iter()
+            .filter(|link| match site_local_url(&link.url, &parse_opts) {
+                Some(local) => !valid_paths.contains(&local.path),
+                None => false,
+            })
+            .cloned()
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let heading_anchors: HashSet<&str> = headings.iter().map(|h| h.anchor.as_str()).collect();
+
+    for (id, range) in &element_ids {
+        if heading_anchors.contains(id.as_str()) {
+            warnings.push(Warning {
+                kind: WarningKind::DuplicateAnchor(id.clone()),
+                range: range.clone(),
+            });
+        }
+    }
+
+    let anchors: HashSet<&str> = heading_anchors
+        .iter()
+        .copied()
+        .chain(element_ids.iter().map(|(id, _)| id.as_str()))
+        .chain(footnote_ids.iter().map(String::as_str))
+        .collect();
+
+    if parse_opts.warn_missing_alt_text {
+        for image in images.iter().filter(|image| image.alt.trim().is_empty()) {
+            warnings.push(Warning {
+                kind: WarningKind::MissingAltText(image.original.clone()),
+                range: image.range.clone(),
+            });
+        }
+    }
+
+    for link in &links {
+        if let Some(local) = site_local_url(&link.url, &parse_opts) {
+            if local.path.starts_with("/..") {
+                warnings.push(Warning {
+                    kind: WarningKind::EscapesRoot(local.path.to_string_lossy().into_owned()),
+                    range: link.range.clone(),
+                });
+            }
+        }
+
+        let fragment = match &link.url {
+            UrlType::Anchor(fragment) => Some(fragment.clone()),
+            url => site_local_url(url, &parse_opts)
+                .filter(|local| is_current_document(&local.path, &parse_opts))
+                .and_then(|local| local.fragment.clone()),
+        };
+
+        if let Some(fragment) = fragment {
+            if !fragment.is_empty() && !anchors.contains(fragment.as_str()) {
+                warnings.push(Warning {
+                    kind: WarningKind::BrokenAnchor(fragment.clone()),
+                    range: link.range.clone(),
+                });
+            }
+        }
+    }
+
+    warnings.sort_by_key(|warning| warning.range.start);
+
+    Markdown {
+        as_html: safe_html,
+        raw_html,
+        links,
+        images,
+        code_blocks,
+        element_ids: element_ids.into_iter().map(|(id, _)| id).collect(),
+        headings,
+        broken_links,
+        warnings,
+    }
+}
+
+/// Undoes smart punctuation, so that heading anchors don't depend on it.
+fn unsmarten(text: &str) -> Cow<'_, str> {
+    if text.is_ascii() {
+        return Cow::Borrowed(text);
+    }
+
+    let mut plain = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '‘' | '’' => plain.push('\''),
+            '“' | '”' => plain.push('"'),
+            '–' => plain.push_str("--"),
+            '—' => plain.push_str("---"),
+            '…' => plain.push_str("..."),
+            c => plain.push(c),
+        }
+    }
+
+    Cow::Owned(plain)
+}
+
+/// Whether two URLs point to the same place, ignoring `.` segments in local
+/// paths.
+fn same_target(a: &UrlType, b: &UrlType) -> bool {
+    match (a, b) {
+        (UrlType::Local(a), UrlType::Local(b)) => {
+            let a_components = a
+                .path
+                .components()
+                .filter(|component| *component != Component::CurDir);
+            let b_components = b
+                .path
+                .components()
+                .filter(|component| *component != Component::CurDir);
+
+            a_components.eq(b_components) && a.query == b.query && a.fragment == b.fragment
+        }
+        _ => a == b,
+    }
+}
+
+/// The local form of a link to the current site, also for links made
+/// absolute by `base_url`.
+fn site_local_url<'u>(url: &'u UrlType, parse_opts: &ParseOptions) -> Option<Cow<'u, LocalUrl>> {
+    match url {
+        UrlType::Local(local) => Some(Cow::Borrowed(local)),
+        UrlType::Remote(remote) if is_on_base_url(remote, parse_opts) => {
+            Some(Cow::Owned(LocalUrl {
+                path: remote.path().into(),
+                query: remote.query().map(String::from),
+                fragment: remote.fragment().map(String::from),
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// Whether a link with an explicit host points to the site at `base_url`.
+fn is_on_base_url(url: &Url, parse_opts: &ParseOptions) -> bool {
+    match &parse_opts.base_url {
+        Some(base_url) => url.origin() == base_url.origin(),
+        None => false,
+    }
+}
+
+/// Resolves a local URL against `base_url`, if set.
+fn absolute_url<'a>(url: CowStr<'a>, parse_opts: &ParseOptions) -> CowStr<'a> {
+    match &parse_opts.base_url {
+        Some(base_url) if !url.is_empty() && !url.starts_with('#') && is_in_local_domain(&url) => {
+            match base_url.join(&url) {
+                Ok(absolute) => absolute.to_string().into(),
+                Err(_) => url,
+            }
+        }
+        _ => url,
+    }
+}
+
+/// Whether a local link path points to the document being parsed.
+fn is_current_document(path: &Path, parse_opts: &ParseOptions) -> bool {
+    path.as_os_str().is_empty() || parse_opts.current_path.as_deref() == Some(path)
+}
+
+/// The extra attributes to put on links that point to other sites.
+fn external_link_attributes(parse_opts: &ParseOptions) -> Vec<(&'static str, String)> {
+    let mut attributes = Vec::new();
+
+    if parse_opts.external_links_in_new_tab {
+        attributes.push(("target", "_blank".to_owned()));
+    }
+
+    if let Some(rel) = external_link_rel(parse_opts) {
+        attributes.push(("rel", rel));
+    }
+
+    if let Some(class) = &parse_opts.external_link_class {
+        attributes.push(("class", class.clone()));
+    }
+
+    attributes
+}
+
+/// The `rel` attribute value for links that point to other sites.
+fn external_link_rel(parse_opts: &ParseOptions) -> Option<String> {
+    let mut rel = Vec::new();
+
+    if parse_opts.external_links_in_new_tab {
+        rel.push("noopener noreferrer");
+    }
+
+    if parse_opts.nofollow_external_links {
+        rel.push("nofollow");
+    }
+
+    if rel.is_empty() {
+        None
+    } else {
+        Some(rel.join(" "))
+    }
+}
+
+/// Renders the opening `<a>` tag the same way pulldown does, with some
+/// extra attributes.
+fn link_start_html(url: &str, title: &str, attributes: &[(&str, String)]) -> String {
+    let mut html = String::from("<a href=\"");
+    escape_href(&mut html, url).unwrap();
+
+    if !title.is_empty() {
+        html.push_str("\" title=\"");
+        escape_html(&mut html, title).unwrap();
+    }
+
+    for (name, value) in attributes {
+        html.push_str("\" ");
+        html.push_str(name);
+        html.push_str("=\"");
+        escape_html(&mut html, value).unwrap();
+    }
+
+    html.push_str("\">");
+    html
+}
+
+/// The size of an image, from the `=WxH` suffix of its source.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ImageSize {
+    width: u32,
+    height: Option<u32>,
+}
+
+/// Finds the `=WxH` size suffixes of images, e.g. `![alt](cat.png =640x360)`
+/// or `![alt](cat.png =640x)`, which the markdown parser doesn't understand.
+///
+/// The suffixes are blanked out with spaces, so that byte offsets into the
+/// returned input still match the original. Returns the sizes keyed by the
+/// offset of the image. Code is left untouched.
+fn strip_image_sizes(input: &str, options: Options) -> (Cow<'_, str>, HashMap<usize, ImageSize>) {
+    let mut sizes = HashMap::new();
+
+    if !IMAGE_SIZE.is_match(input) {
+        return (Cow::Borrowed(input), sizes);
+    }
+
+    let code: Vec<Range<usize>> = Parser::new_ext(input, options)
+        .into_offset_iter()
+        .filter(|(event, _)| matches!(event, Event::Code(_) | Event::Start(Tag::CodeBlock(_))))
+        .map(|(_, range)| range)
+        .collect();
+
+    let mut stripped = input.to_string();
+
+    for captures in IMAGE_SIZE.captures_iter(input) {
+        let image = captures.get(0).unwrap();
+        let suffix = captures.name("suffix").unwrap();
+
+        if code.iter().any(|range| range.contains(&image.start())) {
+            continue;
+        }
+
+        let width = match captures["width"].parse() {
+            Ok(width) => width,
+            Err(_) => continue,
+        };
+        let height = match captures.name("height").map(|height| height.as_str()) {
+            None | Some("") => None,
+            Some(height) => match height.parse() {
+                Ok(height) => Some(height),
+                Err(_) => continue,
+            },
+        };
+
+        sizes.insert(image.start(), ImageSize { width, height });
+        stripped.replace_range(suffix.range(), &" ".repeat(suffix.len()));
+    }
+
+    (Cow::Owned(stripped), sizes)
+}
+
+/// What a `:::` container is rendered as.
+#[derive(Debug, Clone, PartialEq)]
+enum ContainerKind {
+    /// An admonition of the given type.
+    Admonition(String),
+    /// A collapsible section, which may be expanded to begin with.
+    Details { open: bool },
+}
+
+/// The ranges of code blocks and HTML in `input`, where lines mean nothing
+/// to the extensions implemented on top of the markdown parser.
+fn verbatim_ranges(input: &str, options: Options) -> Vec<Range<usize>> {
+    Parser::new_ext(input, options)
+        .into_offset_iter()
+        .filter(|(event, _)| matches!(event, Event::Html(_) | Event::Start(Tag::CodeBlock(_))))
+        .map(|(_, range)| range)
+        .collect()
+}
+
+/// Abbreviations and what they stand for.
+#[derive(Debug)]
+struct Abbreviations {
+    titles: HashMap<String, String>,
+    /// Matches any of the abbreviations, preferring the longest.
+    pattern: Regex,
+}
+
+impl Abbreviations {
+    fn new(titles: HashMap<String, String>) -> Option<Self> {
+        if titles.is_empty() {
+            return None;
+        }
+
+        let mut abbreviations: Vec<&str> = titles.keys().map(String::as_str).collect();
+        abbreviations.sort_by_key(|abbreviation| std::cmp::Reverse(abbreviation.len()));
+
+        let pattern = abbreviations
+            .iter()
+            .map(|abbreviation| regex::escape(abbreviation))
+            .collect::<Vec<_>>()
+            .join("|");
+
+        Some(Abbreviations {
+            titles,
+            pattern: Regex::new(&pattern).unwrap(),
+        })
+    }
+
+    /// Splits `text` into events, with the abbreviations wrapped in `abbr`
+    /// elements. Abbreviations that are part of a longer word or a URL are
+    /// left alone.
+    fn wrap<'a>(&self, text: CowStr<'a>) -> Vec<Event<'a>> {
+        let urls: Vec<Range<usize>> = BARE_URL.find_iter(&text).map(|url| url.range()).collect();
+        let is_word = |c: Option<char>| matches!(c, Some(c) if c.is_alphanumeric() || c == '_');
+
+        let mut events = Vec::new();
+        let mut position = 0;
+
+        for found in self.pattern.find_iter(&text) {
+            if is_word(text[..found.start()].chars().next_back())
+                || is_word(text[found.end()..].chars().next())
+                || urls.iter().any(|url| url.contains(&found.start()))
+            {
+                continue;
+            }
+
+            if found.start() > position {
+                events.push(Event::Text(
+                    text[position..found.start()].to_string().into(),
+                ));
+            }
+
+            let mut html = String::from("<abbr title=\"");
+            escape_html(&mut html, &self.titles[found.as_str()]).unwrap();
+            html.push_str("\">");
+
+            events.push(Event::Html(html.into()));
+            events.push(Event::Text(found.as_str().to_string().into()));
+            events.push(Event::Html("</abbr>".into()));
+            position = found.end();
+        }
+
+        if events.is_empty() {
+            return vec![Event::Text(text)];
+        }
+
+        if position < text.len() {
+            events.push(Event::Text(text[position..].to_string().into()));
+        }
+
+        events
+    }
+}
+
+/// Blanks out abbreviation definitions like `*[HTML]: HyperText Markup
+/// Language` with spaces, so that byte offsets into the returned input still
+/// match the original, and returns the abbreviations. Definitions in code
+/// and HTML are left untouched.
+fn strip_abbreviations(
+    input: Cow<'_, str>,
+    options: Options,
+) -> (Cow<'_, str>, Option<Abbreviations>) {
+    if !ABBREVIATION.is_match(&input) {
+        return (input, None);
+    }
+
+    let verbatim = verbatim_ranges(&input, options);
+    let mut titles = HashMap::new();
+    let mut lines = Vec::new();
+    let mut offset = 0;
+
+    for line in input.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+
+        if verbatim.iter().any(|range| range.contains(&start)) {
+            continue;
+        }
+
+        let captures = match ABBREVIATION.captures(line.trim_end()) {
+            Some(captures) => captures,
+            None => continue,
+        };
+
+        let abbreviation = captures["abbreviation"].to_string();
+        let title = captures["title"].trim();
+
+        if title.is_empty() {
+            titles.remove(&abbreviation);
+        } else {
+            titles.insert(abbreviation, title.to_string());
+        }
+
+        lines.push(start..start + line.trim_end().len());
+    }
+
+    if lines.is_empty() {
+        return (input, None);
+    }
+
+    let mut stripped = input.into_owned();
+
+    for line in lines {
+        let len = line.len();
+        stripped.replace_range(line, &" ".repeat(len));
+    }
+
+    (Cow::Owned(stripped), Abbreviations::new(titles))
+}
+
+/// A `:::` line opening or closing a container.
+#[derive(Debug, Clone, PartialEq)]
+enum ContainerFence {
+    Open {
+        kind: ContainerKind,
+        title: Option<String>,
+    },
+    Close(ContainerKind),
+}
+
+/// Turns the `:::` lines of containers into thematic breaks of the same
+/// length, so that the markdown parser ends any paragraph, list or quote at
+/// the edges of the container. Byte offsets into the returned input still
+/// match the original. Returns the fences by the offset of their line.
+///
+/// Each closing `:::` is matched with the innermost open container. Fences
+/// in code and HTML, those without a match, and those of containers that
+/// aren't enabled are left untouched.
+fn mark_containers<'a>(
+    input: Cow<'a, str>,
+    options: Options,
+    parse_opts: &ParseOptions,
+) -> (Cow<'a, str>, HashMap<usize, ContainerFence>) {
+    let mut fences = HashMap::new();
+
+    if !CONTAINER_FENCE.is_match(&input) {
+        return (input, fences);
+    }
+
+    let verbatim = verbatim_ranges(&input, options);
+
+    // The open containers' lines, and their opening fences. The fence is
+    // missing for containers that aren't enabled.
+    let mut open: Vec<(Range<usize>, Option<ContainerFence>)> = Vec::new();
+    let mut lines = Vec::new();
+    let mut offset = 0;
+
+    for line in input.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+
+        if verbatim.iter().any(|range| range.contains(&start)) {
+            continue;
+        }
+
+        let captures = match CONTAINER_FENCE.captures(line.trim_end()) {
+            Some(captures) => captures,
+            None => continue,
+        };
+
+        let kind = match captures.name("kind") {
+            Some(kind) => kind.as_str(),
+            None => {
+                // A container that isn't enabled is still closed, so that its
+                // fence doesn't close an outer one, but both stay as text.
+                if let Some((open_line, Some(opening))) = open.pop() {
+                    if let ContainerFence::Open { kind, .. } = &opening {
+                        fences.insert(start, ContainerFence::Close(kind.clone()));
+                    }
+                    fences.insert(open_line.start, opening);
+                    lines.push(open_line);
+                    lines.push(start..start + line.trim_end().len());
+                }
+                continue;
+            }
+        };
+
+        let title = captures.name("title").map(|title| title.as_str());
+
+        let container = if kind == "details" && parse_opts.collapsible_sections {
+            let expanded = matches!(
+                title,
+                Some(title) if title == "open" || title.starts_with("open ") || title.starts_with("open\t")
+            );
+            let title = match title {
+                Some(title) if expanded => {
+                    Some(title[4..].trim_start()).filter(|title| !title.is_empty())
+                }
+                title => title,
+            };
+
+            Some(ContainerFence::Open {
+                kind: ContainerKind::Details { open: expanded },
+                title: title.map(str::to_string),
+            })
+        } else if parse_opts.admonitions {
+            Some(ContainerFence::Open {
+                kind: ContainerKind::Admonition(kind.to_string()),
+                title: title.map(str::to_string),
+            })
+        } else {
+            None
+        };
+
+        open.push((start..start + line.trim_end().len(), container));
+    }
+
+    if lines.is_empty() {
+        return (input, fences);
+    }
+
+    let mut marked = input.into_owned();
+
+    for line in lines {
+        let len = line.len();
+        marked.replace_range(line, &"*".repeat(len));
+    }
+
+    (Cow::Owned(marked), fences)
+}
+
+/// The opening HTML of a collapsible section.
+fn details_start_html(open: bool, title: Option<&str>) -> String {
+    let mut html = String::from(if open { "<details open>" } else { "<details>" });
+
+    html.push_str("\n<summary>");
+    escape_html(&mut html, title.unwrap_or("Details")).unwrap();
+    html.push_str("</summary>\n");
+    html
+}
+
+/// The opening HTML of an admonition of the given type.
+fn admonition_start_html(kind: &str, title: Option<&str>, parse_opts: &ParseOptions) -> String {
+    let mut html = String::from("<div class=\"admonition");
+
+    if parse_opts.admonition_types.contains(kind) {
+        html.push(' ');
+        html.push_str(kind);
+    }
+
+    html.push_str("\">\n<p class=\"admonition-title\">");
+
+    match title {
+        Some(title) => escape_html(&mut html, title).unwrap(),
+        None => {
+            let mut chars = kind.chars();
+            if let Some(first) = chars.next() {
+                html.extend(first.to_uppercase());
+            }
+            escape_html(&mut html, chars.as_str()).unwrap();
+        }
+    }
+
+    html.push_str("</p>\n");
+    html
+}
+
+/// Turns the `:` markers of definitions into list markers, so that the
+/// markdown parser reads the definitions of a term as a list right after the
+/// paragraph of terms. Byte offsets into the returned input still match the
+/// original. Returns the offsets of the lines holding definitions.
+///
+/// Definitions must follow a term, optionally after a blank line, or
+/// another definition. Both terms and definitions have to be paragraphs of
+/// their own, so lines in code, quotes or lists are left untouched.
+fn mark_definition_lists(input: Cow<'_, str>, options: Options) -> (Cow<'_, str>, HashSet<usize>) {
+    let mut lines = HashSet::new();
+
+    if !DEFINITION_MARKER.is_match(&input) {
+        return (input, lines);
+    }
+
+    let mut paragraphs = Vec::new();
+    let mut depth = 0;
+
+    for (event, range) in Parser::new_ext(&input, options).into_offset_iter() {
+        match event {
+            Event::Start(tag) => {
+                if depth == 0 && tag == Tag::Paragraph {
+                    paragraphs.push(range);
+                }
+                depth += 1;
+            }
+            Event::End(_) => depth -= 1,
+            _ => (),
+        }
+    }
+
+    let in_paragraph = |offset: usize| paragraphs.iter().any(|range| range.contains(&offset));
+
+    let mut markers = Vec::new();
+    let mut after_term = false;
+    let mut in_definition = false;
+    let mut after_blank = false;
+    let mut offset = 0;
+
+    for line in input.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+
+        if line.trim().is_empty() {
+            after_blank = true;
+            continue;
+        }
+
+        let marker = DEFINITION_MARKER.find(line);
+        let indented = line.starts_with(' ') || line.starts_with('\t');
+
+        if marker.is_some() && in_paragraph(start) && (after_term || in_definition) {
+            markers.push(start + line.find(':').unwrap());
+            lines.insert(start);
+            in_definition = true;
+            after_term = false;
+        } else if !in_definition || (after_blank && !indented) {
+            in_definition = false;
+            after_term = in_paragraph(start);
+        }
+
+        after_blank = false;
+    }
+
+    if markers.is_empty() {
+        return (input, lines);
+    }
+
+    let mut marked = input.into_owned();
+
+    for marker in markers {
+        marked.replace_range(marker..marker + 1, "-");
+    }
+
+    (Cow::Owned(marked), lines)
+}
+
+/// Checks attribute values the sanitizer can't check by itself.
+///
+/// * `data:` URLs are only kept on image sources, for images, and only if
+///   allowed by the options.
+/// * Image dimensions must be plain numbers, e.g. `width="300"`.
+/// * With `span_class_prefix` set, span classes are checked here rather
+///   than by the sanitizer, which can only allow a fixed set of them.
+/// * Iframe sources must be on one of `iframe_hosts`.
+/// * With `footnotes` on, the only ids allowed on `li` and `sup` elements
+///   are those of the document's footnotes and their references.
+/// * Ids on `id_elements` must be slugs.
+/// * Picture source sets may only hold relative or `http(s)` URLs.
+/// * Attributes allowed on custom elements by prefix, e.g. `data-*`, are
+///   dropped from other elements unless they're allowed there by name.
+/// * Media sources must be local, in which case they are rewritten like
+///   images with `media_options`, or on one of `media_hosts`.
+#[derive(Debug, Default)]
+struct AttributePolicy {
+    allow_data_image_urls: bool,
+    span_class_prefix: Option<String>,
+    span_classes: HashSet<String>,
+    iframe_hosts: Vec<String>,
+    /// The ids of the generated footnotes and references, when footnotes
+    /// are on.
+    footnote_ids: Option<HashSet<String>>,
+    id_elements: HashSet<String>,
+    media_hosts: Vec<String>,
+    /// The options media sources are rewritten with, when media elements
+    /// are allowed.
+    media_options: Option<ParseOptions>,
+    /// Attribute prefixes allowed on custom elements, by element.
+    custom_attribute_prefixes: HashMap<String, Vec<String>>,
+    /// The attributes the sanitizer allows by name, by element. The empty
+    /// element holds the attributes allowed everywhere.
+    explicit_attributes: HashMap<String, HashSet<String>>,
+}
+
+impl AttributePolicy {
+    /// Returns `None` to drop the attribute.
+    fn filter<'u>(&self, element: &str, attribute: &str, value: &'u str) -> Option<Cow<'u, str>> {
+        if is_data_url(value)
+            && !(self.allow_data_image_urls
+                && (element, attribute) == ("img", "src")
+                && is_data_image_url(value))
+        {
+            return None;
+        }
+
+        if (element, attribute) == ("iframe", "src")
+            && !is_on_allowed_host(value, &self.iframe_hosts)
+        {
+            return None;
+        }
+
+        if attribute == "id" {
+            let footnote_prefix = match (element, &self.footnote_ids) {
+                ("li", Some(ids)) => Some((FOOTNOTE_ID_PREFIX, ids)),
+                ("sup", Some(ids)) => Some((FOOTNOTE_REFERENCE_ID_PREFIX, ids)),
+                _ => None,
+            };
+            let is_id_element = self.id_elements.contains(element);
+
+            if footnote_prefix.is_some() || is_id_element {
+                let allowed = footnote_prefix
+                    .is_some_and(|(prefix, ids)| value.starts_with(prefix) && ids.contains(value))
+                    || (is_id_element && is_slug(value));
+
+                if !allowed {
+                    return None;
+                }
+            }
+        }
+
+        if self.is_only_allowed_by_custom_prefix(element, attribute) {
+            return None;
+        }
+
+        if (element, attribute) == ("source", "srcset") && !is_safe_srcset(value) {
+            return None;
+        }
+
+        if let Some(options) = &self.media_options {
+            if is_media_url_attribute(element, attribute) {
+                if is_in_local_domain(value) {
+                    let url = image_url(value.into(), LinkType::Inline, options);
+                    return Some(url.into_string().into());
+                }
+
+                if !is_on_allowed_host(value, &self.media_hosts) {
+                    return None;
+                }
+            }
+        }
+
+        if (element == "img" || element == "iframe")
+            && (attribute == "width" || attribute == "height")
+        {
+            let dimension = value.trim();
+
+            if dimension.is_empty() || !dimension.chars().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+
+            return Some(dimension.into());
+        }
+
+        if let Some(prefix) = &self.span_class_prefix {
+            if (element, attribute) == ("span", "class") {
+                let classes: Vec<&str> = value
+                    .split_whitespace()
+                    .filter(|class| {
+                        class.starts_with(prefix.as_str()) || self.span_classes.contains(*class)
+                    })
+                    .collect();
+
+                return Some(classes.join(" ").into());
+            }
+        }
+
+        Some(value.into())
+    }
+
+    fn is_only_allowed_by_custom_prefix(&self, element: &str, attribute: &str) -> bool {
+        let starts_with_any = |prefixes: &Vec<String>| {
+            prefixes
+                .iter()
+                .any(|prefix| attribute.starts_with(prefix.as_str()))
+        };
+
+        if !self.custom_attribute_prefixes.values().any(starts_with_any) {
+            return false;
+        }
+
+        let allowed_by_name = [element, ""].iter().any(|element| {
+            self.explicit_attributes
+                .get(*element)
+                .is_some_and(|attributes| attributes.contains(attribute))
+        });
+
+        !allowed_by_name
+            && !self
+                .custom_attribute_prefixes
+                .get(element)
+                .is_some_and(starts_with_any)
+    }
+}
+
+/// Whether an id is a slug like `pricing-table`.
+fn is_slug(id: &str) -> bool {
+    id.starts_with(|c: char| c.is_ascii_alphabetic())
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// The ids in a piece of raw HTML that the sanitizer keeps, i.e. the slug
+/// ids of elements in `id_elements`.
+fn html_element_ids(html: &str, id_elements: &[String]) -> Vec<String> {
+    ELEMENT_ID
+        .captures_iter(html)
+        .filter(|captures| {
+            id_elements
+                .iter()
+                .any(|element| element.eq_ignore_ascii_case(&captures["tag"]))
+        })
+        .filter_map(|captures| {
+            captures
+                .name("quoted")
+                .or_else(|| captures.name("single_quoted"))
+                .or_else(|| captures.name("unquoted"))
+        })
+        .map(|id| id.as_str().to_string())
+        .filter(|id| is_slug(id))
+        .collect()
+}
+
+const FOOTNOTE_ID_PREFIX: &str = "fn-";
+const FOOTNOTE_REFERENCE_ID_PREFIX: &str = "fnref-";
+
+/// The id of a footnote's definition.
+fn footnote_id(name: &str) -> String {
+    format!("{}{}", FOOTNOTE_ID_PREFIX, name)
+}
+
+/// The id of the first reference to a footnote, which its definition links
+/// back to.
+fn footnote_reference_id(name: &str) -> String {
+    format!("{}{}", FOOTNOTE_REFERENCE_ID_PREFIX, name)
+}
+
+/// A footnote reference's event position, name and range.
+type FootnoteReference = (usize, String, Range<usize>);
+
+/// Fills in the placeholders of footnote references in `events`, numbering
+/// footnotes in the order they're first referenced. References without a
+/// definition stay as text, with a warning.
+fn resolve_footnote_references(
+    events: &mut [Event],
+    references: Vec<FootnoteReference>,
+    defined: &HashSet<String>,
+    numbered: &mut Vec<String>,
+    warnings: &mut Vec<Warning>,
+) {
+    for (position, name, range) in references {
+        if !defined.contains(&name) {
+            events[position] = Event::Text(format!("[^{}]", name).into());
+            warnings.push(Warning {
+                kind: WarningKind::UndefinedFootnote(name),
+                range,
+            });
+            continue;
+        }
+
+        let first = !numbered.contains(&name);
+        if first {
+            numbered.push(name.clone());
+        }
+        let number = numbered.iter().position(|n| *n == name).unwrap() + 1;
+
+        events[position] = Event::Html(footnote_reference_html(&name, number, first).into());
+    }
+}
+
+/// A reference to a footnote. Only the first reference to each footnote
+/// gets an id, for the footnote to link back to.
+fn footnote_reference_html(name: &str, number: usize, first: bool) -> String {
+    let mut html = String::from("<sup class=\"footnote-reference\"");
+    if first {
+        html.push_str(" id=\"");
+        escape_html(&mut html, &footnote_reference_id(name)).unwrap();
+        html.push('"');
+    }
+    html.push_str("><a href=\"#");
+    escape_href(&mut html, &footnote_id(name)).unwrap();
+    html.push_str(&format!("\">{}</a></sup>", number));
+    html
+}
+
+/// Whether `src` is an `http(s)` URL on one of the hosts or their
+/// subdomains. Only the parsed host is compared, so `youtube.com.evil.com`
+/// doesn't pass for `youtube.com`.
+fn is_on_allowed_host(src: &str, hosts: &[String]) -> bool {
+    let url = match Url::parse(src.trim()) {
+        Ok(url) if url.scheme() == "https" || url.scheme() == "http" => url,
+        _ => return false,
+    };
+
+    let host = match url.host_str() {
+        Some(host) => host.to_ascii_lowercase(),
+        None => return false,
+    };
+
+    hosts.iter().any(|allowed| {
+        let allowed = allowed.trim_start_matches('.').to_ascii_lowercase();
+
+        host == allowed
+            || host
+                .strip_suffix(allowed.as_str())
+                .is_some_and(|subdomain| subdomain.ends_with('.'))
+    })
+}
+
+/// Whether every URL in a `srcset` is relative or `http(s)`, which the
+/// sanitizer doesn't check by itself.
+fn is_safe_srcset(srcset: &str) -> bool {
+    srcset
+        .split(',')
+        .filter_map(|candidate| candidate.split_whitespace().next())
+        .all(|url| {
+            is_in_local_domain(url)
+                || matches!(Url::parse(url), Ok(url) if url.scheme() == "https" || url.scheme() == "http")
+        })
+}
+
+fn is_media_url_attribute(element: &str, attribute: &str) -> bool {
+    match attribute {
+        "src" => matches!(element, "video" | "audio" | "source" | "track"),
+        "poster" => element == "video",
+        _ => false,
+    }
+}
+
+/// Removes iframes whose `src` didn't get through the sanitizer, along with
+/// their contents. Relies on the sanitizer's output always quoting
+/// attribute values with `"`.
+fn remove_iframes_without_src(html: &str) -> Cow<'_, str> {
+    IFRAME.replace_all(html, |captures: &regex::Captures| {
+        if captures["attributes"].contains(" src=\"") {
+            captures[0].to_string()
+        } else {
+            String::new()
+        }
+    })
+}
+
+/// The attributes the sanitizer allows by name, by element, with the ones
+/// allowed everywhere under the empty element.
+fn explicit_attributes(builder: &ammonia::Builder) -> HashMap<String, HashSet<String>> {
+    let mut explicit: HashMap<String, HashSet<String>> = HashMap::new();
+
+    for (element, attributes) in builder.clone_tag_attributes() {
+        explicit
+            .entry(element.to_string())
+            .or_default()
+            .extend(attributes.iter().map(|attribute| attribute.to_string()));
+    }
+
+    for (element, values) in builder.clone_tag_attribute_values() {
+        explicit
+            .entry(element.to_string())
+            .or_default()
+            .extend(values.keys().map(|attribute| attribute.to_string()));
+    }
+
+    explicit.insert(
+        String::new(),
+        builder
+            .clone_generic_attributes()
+            .iter()
+            .map(|attribute| attribute.to_string())
+            .collect(),
+    );
+
+    explicit
+}
+
+/// Whether `name` is a valid custom element name, e.g. `doc-callout`. These
+/// always have a hyphen, so they can't clash with built-in tags.
+fn is_custom_element_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name.contains('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '.' | '_'))
+}
+
+fn is_safe_custom_attribute(attribute: &str) -> bool {
+    !attribute.is_empty() && !attribute.starts_with("on") && !matches!(attribute, "class" | "style")
+}
+
+/// Whether the attributes starting with `prefix` can't include event
+/// handlers or `style`.
+fn is_safe_custom_attribute_prefix(prefix: &str) -> bool {
+    !"on".starts_with(prefix) && !prefix.starts_with("on") && !"style".starts_with(prefix)
+}
+
+/// Removes the comments that don't start with `prefix` from sanitized HTML.
+/// Tags are matched too, so that comment markers in attribute values are
+/// skipped over.
+fn remove_comments_without_prefix<'h>(html: &'h str, prefix: &str) -> Cow<'h, str> {
+    COMMENT_OR_TAG.replace_all(html, |captures: &regex::Captures| {
+        match captures.name("comment") {
+            Some(comment) if !comment.as_str().trim_start().starts_with(prefix) => String::new(),
+            _ => captures[0].to_string(),
+        }
+    })
+}
+
+fn is_data_url(url: &str) -> bool {
+    url.trim_start()
+        .get(..5)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("data:"))
+}
+
+/// Whether a `data:` URL holds an image, e.g. `data:image/png;base64,...`.
+fn is_data_image_url(url: &str) -> bool {
+    url.trim_start()
+        .get(..11)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("data:image/"))
+}
+
+/// Writes out an image from its events, with extra attributes.
+fn image_html(events: &[Event], attributes: &[(&str, String)]) -> String {
+    let (url, title) = match events.first() {
+        Some(Event::Start(Tag::Image(_, url, title))) => (url, title),
+        _ => unreachable!("image without a start event"),
+    };
+
+    let mut html = String::from("<img src=\"");
+    escape_href(&mut html, url).unwrap();
+
+    html.push_str("\" alt=\"");
+    for event in &events[1..] {
+        if let Event::Text(text) | Event::Code(text) = event {
+            escape_html(&mut html, text).unwrap();
+        }
+    }
+
+    if !title.is_empty() {
+        html.push_str("\" title=\"");
+        escape_html(&mut html, title).unwrap();
+    }
+
+    for (name, value) in attributes {
+        html.push_str("\" ");
+        html.push_str(name);
+        html.push_str("=\"");
+        escape_html(&mut html, value).unwrap();
+    }
+
+    html.push_str("\" />");
+    html
+}
+
+/// A code block, gathered up so that it can be rendered as a whole.
+struct OpenCodeBlock {
+    /// The info string of a fenced code block, `None` for indented ones.
+    info: Option<String>,
+    fence: FenceInfo,
+    /// The class of the `<pre>` for diagrams, from
+    /// [`ParseOptions::diagram_languages`].
+    diagram_class: Option<String>,
+    code: String,
+    range: Range<usize>,
+}
+
+impl OpenCodeBlock {
+    fn new(kind: &CodeBlockKind, range: Range<usize>) -> Self {
+        let info = match kind {
+            CodeBlockKind::Fenced(info) => Some(info.to_string()),
+            CodeBlockKind::Indented => None,
+        };
+
+        OpenCodeBlock {
+            fence: FenceInfo::parse(info.as_deref().unwrap_or("")),
+            info,
+            diagram_class: None,
+            code: String::new(),
+            range,
+        }
+    }
+
+    /// Whether the block is rendered by a script rather than shown as code.
+    fn is_diagram(&self) -> bool {
+        self.diagram_class.is_some() || self.fence.language == "math"
+    }
+
+    /// Whether the block holds CSV or TSV to render as a table.
+    fn is_table(&self) -> bool {
+        matches!(self.fence.language.as_str(), "csv" | "tsv")
+    }
+
+    fn to_code_block(&self) -> CodeBlock {
+        CodeBlock {
+            lang: self
+                .info
+                .as_deref()
+                .map(|info| fence_language(info).to_string()),
+            info: self.info.clone().unwrap_or_default(),
+            attributes: self.fence.attributes.iter().cloned().collect(),
+            flags: self.fence.flags.clone(),
+            content: self.code.clone(),
+            is_diagram: self.is_diagram(),
+            range: self.range.clone(),
+        }
+    }
+}
+
+/// The language of a fenced code block, i.e. the start of its info string.
+fn fence_language(info: &str) -> &str {
+    let info = info.trim_start();
+    let end = info
+        .find(|c: char| c.is_whitespace() || c == '{')
+        .unwrap_or(info.len());
+
+    &info[..end]
+}
+
+/// The metadata in the info string of a fenced code block, such as
+/// `rust {3,5-7} title="main.rs" linenos`. See [`CodeBlock::attributes`].
+#[derive(Debug, Default, PartialEq)]
+struct FenceInfo {
+    language: String,
+    /// Lines to highlight, from the `{3,5-7}` block.
+    highlighted_lines: Vec<RangeInclusive<usize>>,
+    /// `key="value"` or `key=value` pairs.
+    attributes: Vec<(String, String)>,
+    /// Any other words, like `linenos`.
+    flags: Vec<String>,
+}
+
+impl FenceInfo {
+    fn parse(info: &str) -> Self {
+        let language = fence_language(info);
+        let mut fence = FenceInfo {
+            language: language.to_string(),
+            ..FenceInfo::default()
+        };
+
+        fence.parse_words(&info.trim_start()[language.len()..], false);
+
+        fence
+    }
+
+    /// Parses the words after the language. Inside braces, words are also
+    /// separated by commas, and numbers and ranges are lines to highlight.
+    fn parse_words(&mut self, text: &str, in_braces: bool) {
+        let separator = |c: char| c.is_whitespace() || (in_braces && c == ',');
+        let mut rest = text.trim_start_matches(separator);
+
+        while !rest.is_empty() {
+            let end = match rest.strip_prefix('{') {
+                Some(inner) if !in_braces => {
+                    let end = inner.find('}').unwrap_or(inner.len());
+                    self.parse_words(&inner[..end], true);
+                    (end + 2).min(rest.len())
+                }
+                _ => {
+                    let end = fence_word_end(rest, in_braces);
+                    let word = &rest[..end];
+
+                    if in_braces && word.chars().all(|c| c.is_ascii_digit() || c == '-') {
+                        self.highlighted_lines.extend(parse_line_ranges(word));
+                    } else {
+                        match word.split_once('=') {
+                            Some((key, value)) => self.attributes.push((
+                                key.to_string(),
+                                value.trim_matches(|c| c == '"' || c == '\'').to_string(),
+                            )),
+                            None => self.flags.push(word.to_string()),
+                        }
+                    }
+
+                    end
+                }
+            };
+
+            rest = rest[end..].trim_start_matches(separator);
+        }
+    }
+
+    fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|word| word == flag)
+    }
+
+    /// The title to show above the code, from `title="..."` or
+    /// `filename=...`.
+    fn title(&self) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, value)| (key == "title" || key == "filename") && !value.is_empty())
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The tab label from `tab="..."`, for blocks shown in a tab group.
+    fn tab(&self) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, value)| key == "tab" && !value.is_empty())
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn is_highlighted(&self, line: usize) -> bool {
+        self.highlighted_lines
+            .iter()
+            .any(|lines| lines.contains(&line))
+    }
+}
+
+/// Finds the end of a word in an info string. Quoted values may contain
+/// spaces, as in `title="Getting started.md"`. Inside braces, commas end
+/// words too.
+fn fence_word_end(text: &str, in_braces: bool) -> usize {
+    let mut quote = None;
+
+    for (index, c) in text.char_indices() {
+        match quote {
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c.is_whitespace() || (in_braces && c == ',') => return index,
+            None => {}
+        }
+    }
+
+    text.len()
+}
+
+/// Parses line numbers and ranges like `3,5-7`. Anything that isn't a
+/// number or a range is skipped.
+fn parse_line_ranges(text: &str) -> Vec<RangeInclusive<usize>> {
+    text.split(|c: char| c == ',' || c.is_whitespace())
+        .filter_map(|part| match part.split_once('-') {
+            Some((start, end)) => Some(start.trim().parse().ok()?..=end.trim().parse().ok()?),
+            None => {
+                let line = part.trim().parse().ok()?;
+                Some(line..=line)
+            }
+        })
+        .collect()
+}
+
+/// Replaces the code of a block with the file in its `src` attribute, or
+/// the lines of it given in a `lines` attribute, like `lines="3-20"`. If the
+/// file can't be embedded, the code is replaced with a note saying so.
+fn embed_file(block: &mut OpenCodeBlock, loader: &dyn FileLoader) -> Result<(), WarningKind> {
+    let attribute = |name: &str| {
+        block
+            .fence
+            .attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+    };
+
+    let src = match attribute("src") {
+        Some(src) => src,
+        None => return Ok(()),
+    };
+
+    let contents = match loader.load(&src) {
+        Some(contents) => contents,
+        None => {
+            block.code = format!("Could not embed \"{}\"\n", src);
+            return Err(WarningKind::MissingEmbed(src));
+        }
+    };
+
+    block.code = match attribute("lines") {
+        Some(lines) => match slice_lines(&contents, &lines) {
+            Some(slice) => slice,
+            None => {
+                block.code = format!("Could not embed lines {} of \"{}\"\n", lines, src);
+                return Err(WarningKind::InvalidEmbedLines(lines));
+            }
+        },
+        None => contents,
+    };
+
+    Ok(())
+}
+
+/// The lines of `text` in a range like `3-20`, or a single line like `3`,
+/// counting from 1.
+fn slice_lines(text: &str, lines: &str) -> Option<String> {
+    let (start, end) = match lines.split_once('-') {
+        Some((start, end)) => (start.trim().parse().ok()?, end.trim().parse().ok()?),
+        None => {
+            let line = lines.trim().parse().ok()?;
+            (line, line)
+        }
+    };
+
+    let all: Vec<&str> = text.split_inclusive('\n').collect();
+    if start == 0 || start > end || end > all.len() {
+        return None;
+    }
+
+    let mut slice = all[start - 1..end].concat();
+    if !slice.ends_with('\n') {
+        slice.push('\n');
+    }
+
+    Some(slice)
+}
+
+/// Consecutive code blocks with a `tab` label, shown as one set of tabs.
+struct TabGroup {
+    /// The position of the placeholder event for the group's opening HTML.
+    start: usize,
+    labels: Vec<String>,
+}
+
+fn starts_tabbed_code_block(event: &Event) -> bool {
+    match event {
+        Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+            FenceInfo::parse(info).tab().is_some()
+        }
+        _ => false,
+    }
+}
+
+/// Fills in the opening of a tab group, with a label for each tab, and
+/// closes it.
+fn close_tab_group(events: &mut Vec<Event>, group: TabGroup) {
+    let mut html = String::from("<div class=\"code-tabs\"><div class=\"code-tab-labels\">");
+
+    for label in &group.labels {
+        html.push_str("<span class=\"code-tab-label\" data-tab=\"");
+        escape_html(&mut html, label).unwrap();
+        html.push_str("\">");
+        escape_html(&mut html, label).unwrap();
+        html.push_str("</span>");
+    }
+
+    html.push_str("</div>\n");
+
+    events[group.start] = Event::Html(html.into());
+    events.push(Event::Html(CowStr::Borrowed("</div>\n")));
+}
+
+/// Renders the contents of a `csv` or `tsv` code block as a table. The first
+/// row becomes the header, unless the fence has the `noheader` flag.
+fn csv_table(block: &OpenCodeBlock) -> Result<String, csv::Error> {
+    let delimiter = if block.fence.language == "tsv" {
+        b'\t'
+    } else {
+        b','
+    };
+
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .delimiter(delimiter)
+        .trim(csv::Trim::All)
+        .from_reader(block.code.as_bytes());
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        rows.push(record?);
+    }
+
+    let mut rows = rows.iter();
+    let mut table = String::from("<table>");
+
+    let row_html = |table: &mut String, row: &csv::StringRecord, cell: &str| {
+        table.push_str("<tr>");
+        for field in row {
+            table.push_str(&format!("<{}>", cell));
+            escape_html(&mut *table, field).unwrap();
+            table.push_str(&format!("</{}>", cell));
+        }
+        table.push_str("</tr>");
+    };
+
+    if !block.fence.has_flag("noheader") {
+        if let Some(header) = rows.next() {
+            table.push_str("<thead>");
+            row_html(&mut table, header, "th");
+            table.push_str("</thead>");
+        }
+    }
+
+    table.push_str("<tbody>\n");
+    for row in rows {
+        row_html(&mut table, row, "td");
+        table.push('\n');
+    }
+    table.push_str("</tbody></table>\n");
+
+    Ok(table)
+}
+
+/// The elements wrapping a fenced code block: the container for the copy
+/// button, and the block with a title.
+fn code_block_opening(block: &OpenCodeBlock, parse_opts: &ParseOptions) -> String {
+    let fence = &block.fence;
+    let mut html = String::new();
+
+    if let (Some(class), Some(_)) = (&parse_opts.code_container_class, &block.info) {
+        html.push_str("<div class=\"");
+        escape_html(&mut html, class).unwrap();
+        html.push('"');
+        if !fence.language.is_empty() {
+            html.push_str(" data-lang=\"");
+            escape_html(&mut html, &fence.language).unwrap();
+            html.push('"');
+        }
+        html.push('>');
+    }
+
+    if let Some(title) = fence.title() {
+        html.push_str("<div class=\"code-block\"><div class=\"code-title\">");
+        escape_html(&mut html, title).unwrap();
+        html.push_str("</div>");
+    }
+
+    html
+}
+
+/// Closes the elements opened by `code_block_opening`.
+fn code_block_closing(block: &OpenCodeBlock, parse_opts: &ParseOptions) -> String {
+    let mut html = String::new();
+
+    if block.fence.title().is_some() {
+        html.push_str("</div>");
+    }
+
+    if parse_opts.code_container_class.is_some() && block.info.is_some() {
+        html.push_str("</div>");
+    }
+
+    if !html.is_empty() {
+        html.push('\n');
+    }
+
+    html
+}
+
+/// The `<pre><code>` of a code block. Untagged fences get the default
+/// language, aliases their canonical language, and indented blocks a
+/// `code-indented` class.
+fn code_block_start<'a>(block: &OpenCodeBlock, kind: CodeBlockKind<'a>) -> Event<'a> {
+    let language = &block.fence.language;
+
+    match kind {
+        CodeBlockKind::Indented => {
+            let mut html = String::from("<pre><code class=\"");
+            if !language.is_empty() {
+                html.push_str("language-");
+                escape_html(&mut html, language).unwrap();
+                html.push(' ');
+            }
+            html.push_str("code-indented\">");
+
+            Event::Html(html.into())
+        }
+        CodeBlockKind::Fenced(info) if fence_language(&info) != language => Event::Start(
+            Tag::CodeBlock(CodeBlockKind::Fenced(language.clone().into())),
+        ),
+        kind => Event::Start(Tag::CodeBlock(kind)),
+    }
+}
+
+fn code_block_end(kind: CodeBlockKind) -> Event {
+    match kind {
+        CodeBlockKind::Indented => Event::Html(CowStr::Borrowed("</code></pre>\n")),
+        kind => Event::End(Tag::CodeBlock(kind)),
+    }
+}
+
+/// Renders the contents of a code block, i.e. what goes inside its
+/// `<pre><code>`.
+fn code_block_contents(block: OpenCodeBlock, parse_opts: &ParseOptions) -> Event<'static> {
+    let mut code = block.code.clone();
+    if let Some(width) = parse_opts.code_tab_width {
+        code = expand_tabs(&code, width);
+    }
+    let language = block.fence.language.as_str();
+
+    // Diffs can hold code in another language, as in `diff-rust`.
+    let diff_language = match language.strip_prefix("diff") {
+        Some("") => Some(""),
+        Some(rest) => rest.strip_prefix('-'),
+        None => None,
+    };
+
+    let shell = parse_opts.shell_prompts
+        && matches!(language, "console" | "shell" | "bash" | "sh")
+        && code.lines().any(|line| shell_prompt_len(line) > 0);
+
+    let line_numbers = parse_opts.code_line_numbers || block.fence.has_flag("linenos");
+
+    if !line_numbers
+        && block.fence.highlighted_lines.is_empty()
+        && diff_language.is_none()
+        && !shell
+    {
+        return match highlight_code_block(language, &code, parse_opts) {
+            Some(html) => Event::Html(html.into()),
+            None => Event::Text(code.into()),
+        };
+    }
+
+    // The `+`, `-` and `@@` markers at the start of diff lines, and shell
+    // prompts, are kept out of the code so that it can be highlighted as
+    // usual. Hunk and file headers, like `--- a/src/lib.rs`, are markers in
+    // their entirety.
+    let (markers, code): (Vec<&str>, String) = match diff_language.is_some() || shell {
+        true => {
+            let mut markers = Vec::new();
+            let mut stripped = String::with_capacity(code.len());
+
+            for line in code.split_inclusive('\n') {
+                let marker_len = if shell {
+                    shell_prompt_len(line)
+                } else if line.starts_with("@@")
+                    || line.starts_with("--- ")
+                    || line.starts_with("+++ ")
+                {
+                    line.trim_end_matches('\n').len()
+                } else if line.starts_with(['+', '-', ' ']) {
+                    1
+                } else {
+                    0
+                };
+
+                markers.push(&line[..marker_len]);
+                stripped.push_str(&line[marker_len..]);
+            }
+
+            (markers, stripped)
+        }
+        false => (Vec::new(), code.to_string()),
+    };
+
+    let html = highlight_code_block(diff_language.unwrap_or(language), &code, parse_opts)
+        .unwrap_or_else(|| {
+            let mut html = String::new();
+            escape_html(&mut html, &code).unwrap();
+            html
+        });
+
+    let mut lines = html_lines(&html);
+    // A diff line with nothing but its marker leaves no code behind.
+    lines.resize(lines.len().max(markers.len()), String::new());
+
+    let mut wrapped = String::with_capacity(html.len());
+
+    for (index, line) in lines.iter().enumerate() {
+        let number = index + 1;
+        let marker = markers.get(index).copied().unwrap_or("");
+
+        let mut classes = Vec::new();
+        if line_numbers || !block.fence.highlighted_lines.is_empty() {
+            classes.push("line");
+        }
+        if block.fence.is_highlighted(number) {
+            classes.push("hl-line");
+        }
+        if shell {
+            if marker.is_empty() {
+                classes.push("output");
+            }
+        } else if marker.starts_with("@@") {
+            classes.push("diff-hunk");
+        } else if marker.starts_with("--- ") || marker.starts_with("+++ ") {
+            classes.push("diff-header");
+        } else if marker == "+" {
+            classes.push("diff-add");
+        } else if marker == "-" {
+            classes.push("diff-del");
+        }
+
+        let mut marker_html = String::new();
+        if shell && !marker.is_empty() {
+            marker_html.push_str("<span class=\"prompt\">");
+            escape_html(&mut marker_html, marker).unwrap();
+            marker_html.push_str("</span>");
+        } else {
+            escape_html(&mut marker_html, marker).unwrap();
+        }
+
+        if classes.is_empty() {
+            wrapped.push_str(&marker_html);
+            wrapped.push_str(line);
+            wrapped.push('\n');
+            continue;
+        }
+
+        wrapped.push_str(&format!("<span class=\"{}\"", classes.join(" ")));
+        if line_numbers {
+            wrapped.push_str(&format!(" data-line=\"{}\"", number));
+        }
+        wrapped.push('>');
+        wrapped.push_str(&marker_html);
+        wrapped.push_str(line);
+        wrapped.push_str("</span>\n");
+    }
+
+    Event::Html(wrapped.into())
+}
+
+/// The length of the `$ ` prompt at the start of a line of a shell session,
+/// or 0 for lines of output.
+fn shell_prompt_len(line: &str) -> usize {
+    if line.starts_with("$ ") {
+        2
+    } else if line.trim_end() == "$" {
+        1
+    } else {
+        0
+    }
+}
+
+/// Replaces tabs with spaces up to the next tab stop, so that text after
+/// the tabs stays aligned.
+fn expand_tabs(code: &str, width: u8) -> String {
+    let width = usize::from(width.max(1));
+    let mut expanded = String::with_capacity(code.len());
+    let mut column = 0;
+
+    for c in code.chars() {
+        match c {
+            '\t' => {
+                let spaces = width - column % width;
+                expanded.push_str(&" ".repeat(spaces));
+                column += spaces;
+            }
+            '\n' => {
+                expanded.push(c);
+                column = 0;
+            }
+            _ => {
+                expanded.push(c);
+                column += 1;
+            }
+        }
+    }
+
+    expanded
+}
+
+/// Splits the HTML of a code block into lines. Spans still open at the end
+/// of a line are closed and opened again on the next one, so that each
+/// line can be wrapped in an element of its own. The newline at the end of
+/// the code doesn't start another line.
+fn html_lines(html: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut open_spans: Vec<&str> = Vec::new();
+    let mut line = String::new();
+    let mut line_has_text = false;
+    let mut rest = html;
+
+    while !rest.is_empty() {
+        if rest.starts_with("</span>") {
+            open_spans.pop();
+            line.push_str("</span>");
+            rest = &rest["</span>".len()..];
+        } else if rest.starts_with("<span") {
+            let end = rest.find('>').map_or(rest.len(), |end| end + 1);
+            open_spans.push(&rest[..end]);
+            line.push_str(&rest[..end]);
+            rest = &rest[end..];
+        } else if let Some(after_newline) = rest.strip_prefix('\n') {
+            for _ in &open_spans {
+                line.push_str("</span>");
+            }
+            lines.push(std::mem::take(&mut line));
+            line_has_text = false;
+
+            for span in &open_spans {
+                line.push_str(span);
+            }
+            rest = after_newline;
+        } else {
+            let first = rest.chars().next().unwrap().len_utf8();
+            let end = rest[first..]
+                .find(['<', '\n'])
+                .map_or(rest.len(), |end| end + first);
+            line.push_str(&rest[..end]);
+            line_has_text = true;
+            rest = &rest[end..];
+        }
+    }
+
+    if line_has_text {
+        lines.push(line);
+    }
+
+    lines
+}
+
+#[cfg(feature = "syntax-highlighting")]
+lazy_static! {
+    static ref SYNTAX_SET: syntect::parsing::SyntaxSet =
+        syntect::parsing::SyntaxSet::load_defaults_newlines();
+    static ref THEME_SET: syntect::highlighting::ThemeSet =
+        syntect::highlighting::ThemeSet::load_defaults();
+    static ref CLASS_ATTRIBUTE: Regex = Regex::new(r#"class="([^"]*)""#).unwrap();
+}
+
+#[cfg(feature = "syntax-highlighting")]
+fn highlight_code_block(language: &str, code: &str, parse_opts: &ParseOptions) -> Option<String> {
+    let highlighting = parse_opts.syntax_highlighting.as_ref()?;
+    highlight_code(code, language, highlighting)
+}
+
+#[cfg(not(feature = "syntax-highlighting"))]
+fn highlight_code_block(
+    _language: &str,
+    _code: &str,
+    _parse_opts: &ParseOptions,
+) -> Option<String> {
+    None
+}
+
+/// Highlights code in the given language. Returns `None` for languages
+/// that aren't known, or themes that don't exist, so that the code is
+/// rendered as is.
+#[cfg(feature = "syntax-highlighting")]
+fn highlight_code(code: &str, language: &str, highlighting: &SyntaxHighlighting) -> Option<String> {
+    use syntect::easy::HighlightLines;
+    use syntect::html::{
+        styled_line_to_highlighted_html, ClassStyle, ClassedHTMLGenerator, IncludeBackground,
+    };
+    use syntect::util::LinesWithEndings;
+
+    if language.is_empty() {
+        return None;
+    }
+
+    let syntax = SYNTAX_SET.find_syntax_by_token(language)?;
+
+    match highlighting {
+        SyntaxHighlighting::Classes { prefix } => {
+            let mut generator =
+                ClassedHTMLGenerator::new_with_class_style(syntax, &SYNTAX_SET, ClassStyle::Spaced);
+
+            for line in LinesWithEndings::from(code) {
+                generator
+                    .parse_html_for_line_which_includes_newline(line)
+                    .ok()?;
+            }
+
+            let html = generator.finalize();
+
+            Some(
+                CLASS_ATTRIBUTE
+                    .replace_all(&html, |captures: &regex::Captures| {
+                        let classes: Vec<String> = captures[1]
+                            .split_whitespace()
+                            .map(|class| format!("{}{}", prefix, class))
+                            .collect();
+
+                        format!("class=\"{}\"", classes.join(" "))
+                    })
+                    .into_owned(),
+            )
+        }
+        SyntaxHighlighting::Theme(name) => {
+            let theme = THEME_SET.themes.get(name)?;
+            let mut highlighter = HighlightLines::new(syntax, theme);
+            let mut html = String::new();
+
+            for line in LinesWithEndings::from(code) {
+                let regions = highlighter.highlight_line(line, &SYNTAX_SET).ok()?;
+                html.push_str(
+                    &styled_line_to_highlighted_html(&regions, IncludeBackground::No).ok()?,
+                );
+            }
+
+            Some(html)
+        }
+    }
+}
+
+/// The prefix of the classes in highlighted code, if it uses classes.
+#[cfg(feature = "syntax-highlighting")]
+fn highlight_class_prefix(parse_opts: &ParseOptions) -> Option<&str> {
+    match &parse_opts.syntax_highlighting {
+        Some(SyntaxHighlighting::Classes { prefix }) => Some(prefix),
+        _ => None,
+    }
+}
+
+#[cfg(not(feature = "syntax-highlighting"))]
+fn highlight_class_prefix(_parse_opts: &ParseOptions) -> Option<&str> {
+    None
+}
+
+/// Whether highlighted code is colored with inline styles.
+#[cfg(feature = "syntax-highlighting")]
+fn highlight_uses_inline_styles(parse_opts: &ParseOptions) -> bool {
+    matches!(
+        &parse_opts.syntax_highlighting,
+        Some(SyntaxHighlighting::Theme(_))
+    )
+}
+
+#[cfg(not(feature = "syntax-highlighting"))]
+fn highlight_uses_inline_styles(_parse_opts: &ParseOptions) -> bool {
+    false
+}
+
+/// Wraps an image in a link to its full size version, for the lightbox.
+fn zoom_link_html(image_start: &Event, image_html: String) -> String {
+    let url = match image_start {
+        Event::Start(Tag::Image(_, url, _)) => url,
+        _ => unreachable!("image without a start event"),
+    };
+
+    let mut html = String::from("<a href=\"");
+    escape_href(&mut html, url).unwrap();
+    html.push_str("\" class=\"zoom\">");
+    html.push_str(&image_html);
+    html.push_str("</a>");
+    html
+}
+
+/// Converts a byte offset into the markdown source, such as the start of
+/// `Link::range`, into a 1-based line and column number. The column counts
+/// characters, not bytes.
+pub fn line_and_column(input: &str, offset: usize) -> (usize, usize) {
+    let before = &input[..offset.min(input.len())];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|index| index + 1).unwrap_or(0);
+    let column = before[line_start..].chars().count() + 1;
+
+    (line, column)
+}
+
+/// Rewrites an image like [`rewrite_link`], then adds URL parameters and the base URL.
+fn rewrite_image<'a>(
+    link_type: LinkType,
+    url: CowStr<'a>,
+    title: CowStr<'a>,
+    parse_opts: &'a ParseOptions,
+) -> (LinkType, CowStr<'a>, CowStr<'a>) {
+    let (link_type, url, title) = rewrite_link(link_type, url, title, LinkKind::Image, parse_opts);
+
+    let url = if parse_opts.url_params_on_images && accepts_parameters(&url, parse_opts) {
+        append_parameters(url, parse_opts)
+    } else {
+        url
+    };
+
+    (link_type, absolute_url(url, parse_opts), title)
+}
+
+/// The final source of an image, from [`rewrite_image`].
+fn image_url<'a>(url: CowStr<'a>, link_type: LinkType, parse_opts: &'a ParseOptions) -> CowStr<'a> {
+    rewrite_image(link_type, url, CowStr::Borrowed(""), parse_opts).1
+}
+
+/// Rewrites the link by either setting a different root path, or by
+/// swapping the whole URL if the link resolver or a rewrite rule provides
+/// a replacement.
+fn rewrite_link<'a>(
+    link_type: LinkType,
+    url: CowStr<'a>,
+    title: CowStr<'a>,
+    kind: LinkKind,
+    parse_opts: &'a ParseOptions,
+) -> (LinkType, CowStr<'a>, CowStr<'a>) {
+    if let Some(resolved) = parse_opts
+        .hooks
+        .link_resolver
+        .as_ref()
+        .and_then(|resolver| resolver.resolve(&url, kind))
+    {
+        (
+            link_type,
+            apply_url_root(resolved.into(), kind, parse_opts),
+            title,
+        )
+    } else if let Some((matching_link, rule_title)) = find_rewrite_rule(
+        &url,
+        &parse_opts.link_rewrite_rules,
+        parse_opts.rewrite_rules_ignore_trailing_slash,
+    ) {
+        (
+            link_type,
+            apply_rule_url_root(matching_link, kind, parse_opts),
+            rule_title.map_or(title, CowStr::from),
+        )
+    } else if let Some((matching_link, rule_title)) = parse_opts
+        .regex_rewrite_rules
+        .iter()
+        .find_map(|rule| Some((rule.apply(&url)?, rule.title.as_deref())))
+    {
+        (
+            link_type,
+            apply_rule_url_root(matching_link, kind, parse_opts),
+            rule_title.map_or(title, CowStr::from),
+        )
+    } else if let Some(directory) = directory_index_url(&url).filter(|_| kind == LinkKind::Link) {
+        (
+            link_type,
+            apply_url_root(directory.into(), kind, parse_opts),
+            title,
+        )
+    } else {
+        (link_type, apply_url_root(url, kind, parse_opts), title)
+    }
+}
+
+/// Maps links to `README.md` and `index.md` files, which become the index
+/// page of their directory on the generated site, to the directory itself.
+/// Any query string or fragment is kept.
+fn directory_index_url(url: &str) -> Option<String> {
+    if url.starts_with('#') || !is_in_local_domain(url) {
+        return None;
+    }
+
+    let (path, rest) = url.split_at(url.find(['?', '#']).unwrap_or(url.len()));
+    let (directory, file) = match path.rfind('/') {
+        Some(index) => path.split_at(index + 1),
+        None => ("./", path),
+    };
+
+    if file.eq_ignore_ascii_case("readme.md") || file.eq_ignore_ascii_case("index.md") {
+        Some(format!("{}{}", directory, rest))
+    } else {
+        None
+    }
+}
+
+/// Applies the URL root to the result of a rewrite rule, if the options ask
+/// for it.
+fn apply_rule_url_root<'a>(
+    rewritten: String,
+    kind: LinkKind,
+    parse_opts: &ParseOptions,
+) -> CowStr<'a> {
+    if parse_opts.url_root_after_rewrite {
+        apply_url_root(rewritten.into(), kind, parse_opts)
+    } else {
+        rewritten.into()
+    }
+}
+
+/// Prepends `url_root`, or `image_root` for images when set, to
+/// root-relative URLs.
+fn apply_url_root<'a>(url: CowStr<'a>, kind: LinkKind, parse_opts: &ParseOptions) -> CowStr<'a> {
+    let root = match (kind, &parse_opts.image_root) {
+        (LinkKind::Image, Some(image_root)) => image_root,
+        _ => &parse_opts.url_root,
+    };
+
+    if url.starts_with('/') && !is_protocol_relative(&url) {
+        join_url_root(root, &url).into()
+    } else {
+        url
+    }
+}
+
+/// Looks up the replacement for a URL in the rewrite rules, first by exact
+/// match and then by the longest matching prefix rule. Returns the
+/// rewritten URL along with the title of the rule that matched, if any.
+///
+/// Both the URL and the rule keys are compared in their normalized form, see
+/// `normalize_rule_url`.
+fn find_rewrite_rule<'r>(
+    url: &str,
+    rules: &'r HashMap<String, LinkRewrite>,
+    ignore_trailing_slash: bool,
+) -> Option<(String, Option<&'r str>)> {
+    let candidate = normalize_rule_url(url);
+    let same = |key: &str| {
+        let key = normalize_rule_url(key);
+
+        key == candidate
+            || (ignore_trailing_slash
+                && key.len() > 1
+                && key.trim_end_matches('/') == candidate.trim_end_matches('/'))
+    };
+
+    let exact = rules.get_key_value(url).or_else(|| {
+        rules
+            .iter()
+            .filter(|(key, _)| same(key))
+            .min_by_key(|(key, _)| key.as_str())
+    });
+
+    if let Some((_, rule)) = exact {
+        return Some((
+            expand_rewrite_template(&rule.url, url, candidate.trim_start_matches('/')),
+            rule.title.as_deref(),
+        ));
+    }
+
+    rules
+        .iter()
+        .filter(|(prefix, _)| prefix.ends_with('/'))
+        .map(|(prefix, replacement)| (prefix, normalize_rule_url(prefix), replacement))
+        .filter(|(_, normalized, _)| candidate.starts_with(normalized.as_ref()))
+        .max_by(|(a, a_normalized, _), (b, b_normalized, _)| {
+            a_normalized
+                .len()
+                .cmp(&b_normalized.len())
+                .then_with(|| b.cmp(a))
+        })
+        .map(|(_, normalized, rule)| {
+            let remainder = &candidate[normalized.len()..];
+
+            let rewritten = if REWRITE_PLACEHOLDER.is_match(&rule.url) {
+                expand_rewrite_template(&rule.url, url, remainder)
+            } else {
+                let mut rewritten = rule.url.clone();
+                rewritten.push_str(remainder);
+                rewritten
+            };
+
+            (rewritten, rule.title.as_deref())
+        })
+}
+
+/// Normalizes the path of a local URL for matching against rewrite rules:
+/// duplicate slashes are collapsed and `.` segments are dropped, so that
+/// `./assets/cat.jpg` and `/assets//cat.jpg` both become `/assets/cat.jpg`.
+/// Remote URLs, queries and fragments are left alone.
+fn normalize_rule_url(url: &str) -> Cow<'_, str> {
+    if url.starts_with('#') || !is_in_local_domain(url) {
+        return Cow::Borrowed(url);
+    }
+
+    let (path, rest) = url.split_at(url.find(['?', '#']).unwrap_or(url.len()));
+
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .collect();
+
+    if segments.is_empty() && !path.starts_with('/') && !path.starts_with("./") {
+        return Cow::Borrowed(url);
+    }
+
+    let mut normalized = String::with_capacity(url.len());
+
+    if path.starts_with('/') || path.starts_with("./") || path == "." {
+        normalized.push('/');
+    }
+    normalized.push_str(&segments.join("/"));
+    if path.ends_with('/') && !segments.is_empty() {
+        normalized.push('/');
+    }
+    normalized.push_str(rest);
+
+    if normalized == url {
+        Cow::Borrowed(url)
+    } else {
+        Cow::Owned(normalized)
+    }
+}
+
+/// Fills in the placeholders of a rewrite rule replacement:
+///
+/// * `{url}` - the original URL
+/// * `{path}` - the part of the URL after the matched prefix, or the URL
+///   without its leading slash for exact rules
+/// * `{filename}` - the last path segment of the URL
+///
+/// Anything else in braces is left as is.
+fn expand_rewrite_template(template: &str, url: &str, path: &str) -> String {
+    REWRITE_PLACEHOLDER
+        .replace_all(template, |captures: &regex::Captures| {
+            match &captures["name"] {
+                "url" => url.to_owned(),
+                "path" => path.to_owned(),
+                _ => url_filename(url).to_owned(),
+            }
+        })
+        .into_owned()
+}
+
+fn url_filename(url: &str) -> &str {
+    let path = url.split(['?', '#']).next().unwrap_or("");
+
+    path.rsplit('/').next().unwrap_or("")
+}
+
+/// Prepends the URL root to a root-relative path.
+///
+/// URL paths are joined as plain strings with `/` separators, rather than
+/// as filesystem paths, so that the result is the same on every platform.
+/// Exactly one slash separates the root from the path, regardless of
+/// whether either side already had one.
+fn join_url_root(root: &str, path: &str) -> String {
+    let root = root.trim_end_matches('/');
+    let path = path.trim_start_matches('/');
+
+    let mut joined = String::with_capacity(root.len() + path.len() + 1);
+    joined.push_str(root);
+    joined.push('/');
+    joined.push_str(path);
+
+    joined
+}
+
+/// Appends the configured `url_params` to the URL's query string.
+///
+/// If the URL already has a query string the parameters are merged into
+/// it. Parameters the URL already sets are left alone, so the value written
+/// in the markdown wins over the configured one. Any fragment stays at the
+/// end, giving `path?existing&added#fragment`.
+fn append_parameters<'a>(url: CowStr<'a>, parse_opts: &'a ParseOptions) -> CowStr<'a> {
+    let (target, fragment) = url.split_at(url.find('#').unwrap_or(url.len()));
+
+    let existing_keys: HashSet<&str> = match target.split_once('?') {
+        Some((_, query)) => query
+            .split('&')
+            .filter_map(|pair| pair.split('=').next())
+            .collect(),
+        None => HashSet::new(),
+    };
+
+    let mut appended = target.to_string();
+
+    for (key, value) in &parse_opts.url_params {
+        if existing_keys.contains(key.as_str()) {
+            continue;
+        }
+
+        if !appended.contains('?') {
+            appended.push('?');
+        } else if !appended.ends_with('?') && !appended.ends_with('&') {
+            appended.push('&');
+        }
+
+        appended.push_str(key);
+        appended.push('=');
+        appended.push_str(value);
+    }
+
+    appended.push_str(fragment);
+
+    appended.into()
+}
+
+/// Splits text into plain text and autolinks for every bare URL in it.
+///
+/// Trailing punctuation is not considered part of the URL, and neither is a
+/// closing parenthesis without a matching opening one.
+fn autolink_bare_urls<'a>(text: &str, range: Range<usize>) -> Vec<(Event<'a>, Range<usize>)> {
+    // Only when the text appears verbatim in the source can the position of
+    // each piece be worked out. Otherwise every piece gets the whole range.
+    let verbatim = text.len() == range.len();
+    let sub_range = |start: usize, end: usize| {
+        if verbatim {
+            range.start + start..range.start + end
+        } else {
+            range.clone()
+        }
+    };
+
+    let mut events = Vec::new();
+    let mut position = 0;
+
+    for found in BARE_URL.find_iter(text) {
+        let mut url = found.as_str();
+
+        loop {
+            let trimmed =
+                url.trim_end_matches(['?', '!', '.', ',', ':', ';', '*', '_', '~', '\'', '"']);
+            let trimmed = if trimmed.ends_with(')')
+                && trimmed.matches(')').count() > trimmed.matches('(').count()
+            {
+                &trimmed[..trimmed.len() - 1]
+            } else {
+                trimmed
+            };
+
+            if trimmed.len() == url.len() {
+                break;
+            }
+            url = trimmed;
+        }
+
+        let href = if url.starts_with("www.") {
+            format!("http://{}", url)
+        } else {
+            url.to_owned()
+        };
+        let end = found.start() + url.len();
+
+        if found.start() > position {
+            events.push((
+                Event::Text(text[position..found.start()].to_owned().into()),
+                sub_range(position, found.start()),
+            ));
+        }
+
+        events.push((
+            Event::Start(Tag::Link(
+                LinkType::Autolink,
+                href.clone().into(),
+                "".into(),
+            )),
+            sub_range(found.start(), end),
+        ));
+        events.push((
+            Event::Text(url.to_owned().into()),
+            sub_range(found.start(), end),
+        ));
+        events.push((
+            Event::End(Tag::Link(LinkType::Autolink, href.into(), "".into())),
+            sub_range(found.start(), end),
+        ));
+
+        position = end;
+    }
+
+    if position < text.len() {
+        events.push((
+            Event::Text(text[position..].to_owned().into()),
+            sub_range(position, text.len()),
+        ));
+    }
+
+    events
+}
+
+/// Works out whether the URL points to a local file, a remote resource, or
+/// an email address. Protocol-relative URLs are resolved as `https:`.
+/// Email autolinks are stored without their `mailto:` scheme by pulldown,
+/// so it gets added back here.
+fn classify_url(url: &str, link_type: LinkType) -> Option<UrlType> {
+    if link_type == LinkType::Email {
+        return classify_url(&format!("mailto:{}", url), LinkType::Inline);
+    }
+
+    if let Some(fragment) = url.strip_prefix('#') {
+        return Some(UrlType::Anchor(fragment.to_owned()));
+    }
+
+    if is_protocol_relative(url) {
+        return Url::parse(&format!("https:{}", url))
+            .ok()
+            .map(UrlType::Remote);
+    }
+
+    match Url::parse(url) {
+        Ok(url) if url.scheme() == "mailto" => Some(UrlType::Email(url.path().to_owned())),
+        Ok(url) => Some(UrlType::Remote(url)),
+        Err(ParseError::EmptyHost) | Err(ParseError::RelativeUrlWithoutBase) => {
+            Some(UrlType::Local(LocalUrl::from(url)))
+        }
+        Err(_) => None,
+    }
+}
+
+/// Whether `url_params` should be appended to the URL. Fragment-only and
+/// empty links stay within the current page, so they never get parameters.
+fn accepts_parameters(url: &str, parse_opts: &ParseOptions) -> bool {
+    !parse_opts.url_params.is_empty()
+        && !url.is_empty()
+        && !url.starts_with('#')
+        && !parse_opts
+            .url_params_exempt_prefixes
+            .iter()
+            .any(|prefix| url.starts_with(prefix.as_str()))
+        && is_in_local_domain(url)
+}
+
+/// Whether the URL is scheme-relative, like `//cdn.example.com/lib.js`.
+/// These point to another host even though they start with a slash.
+fn is_protocol_relative(url: &str) -> bool {
+    url.starts_with("//")
+}
+
+/// Whether the URL points to the current site. Any URL with an explicit
+/// scheme, such as `tel:` or `slack://`, points elsewhere even when it has
+/// no host.
+fn is_in_local_domain(url_string: &str) -> bool {
+    if is_protocol_relative(url_string) {
+        return false;
+    }
+
+    match Url::parse(url_string) {
+        Ok(_) => false,
+        Err(url::ParseError::RelativeUrlWithoutBase) => true,
+        Err(url::ParseError::EmptyHost) => true,
+        Err(_) => false,
+    }
+}
+
+lazy_static! {
+    static ref IMAGE_SIZE: Regex = Regex::new(
+        r#"!\[[^\]]*\]\(\s*(?:<[^>\n]*>|[^\s()]+)(?:\s+(?:"[^"\n]*"|'[^'\n]*'))?(?P<suffix>\s+=(?P<width>\d+)x(?P<height>\d*))(?:\s+(?:"[^"\n]*"|'[^'\n]*'))?\s*\)"#
+    )
+    .unwrap();
+    static ref CONTAINER_FENCE: Regex = Regex::new(
+        r"(?m)^:{3,}[ \t]*(?:(?P<kind>[a-zA-Z][a-zA-Z0-9_-]*)(?:[ \t]+(?P<title>.*?))?)?[ \t]*$"
+    )
+    .unwrap();
+    static ref ABBREVIATION: Regex =
+        Regex::new(r"(?m)^\*\[(?P<abbreviation>[^\]\s](?:[^\]\n]*[^\]\s])?)\]:(?P<title>.*)$").unwrap();
+    static ref DEFINITION_MARKER: Regex = Regex::new(r"(?m)^ {0,3}:[ \t]+\S").unwrap();
+    static ref BARE_URL: Regex = Regex::new(r"\b(?:https?://|www\.)[^\s<>]+").unwrap();
+    static ref HTML_LINK_TAG: Regex = Regex::new(r"(?i)<(?P<closing>/)?a(?:\s[^>]*)?>").unwrap();
+    static ref IFRAME: Regex =
+        Regex::new(r#"(?s)<iframe(?P<attributes>(?:\s+[^\s=>]+="[^"]*")*)\s*>.*?</iframe>"#)
+            .unwrap();
+    static ref ELEMENT_ID: Regex = Regex::new(
+        r#"<(?P<tag>[a-zA-Z][a-zA-Z0-9-]*)\b[^>]*?\sid\s*=\s*(?:"(?P<quoted>[^"]*)"|'(?P<single_quoted>[^']*)'|(?P<unquoted>[^\s"'=<>`]+))"#
+    )
+    .unwrap();
+    static ref COMMENT_OR_TAG: Regex = Regex::new(
+        r#"(?s)<!--(?P<comment>.*?)-->|</?[a-zA-Z][a-zA-Z0-9-]*(?:\s+[^\s=>]+="[^"]*")*\s*/?>"#
+    )
+    .unwrap();
+    static ref SNIPPET_CLASSES: Regex = Regex::new(r#"<span[^>]*\sclass="([^"]*)""#).unwrap();
+    static ref REWRITE_PLACEHOLDER: Regex = Regex::new(r"\{(?P<name>url|path|filename)\}").unwrap();
+    static ref CALLOUT_PATTERN_START: Regex =
+        Regex::new(r"^\{%\s*(?P<type>\w+)\s*(?P<title>.*)\s*%\}$").unwrap();
+    static ref CALLOUT_PATTERN_END: Regex = Regex::new(r"\{%\s*end\s*%\}").unwrap();
+}
+
+fn is_callout_start(text: &str) -> bool {
+    parse_callout(text).is_some()
+}
+
+fn is_callout_end(text: &str) -> bool {
+    CALLOUT_PATTERN_END.is_match(text)
+}
+
+fn is_callout_close_event(event: Option<&Event>) -> bool {
+    event == Some(&Event::Html(CowStr::from(format!("</div>"))))
+}
+
+fn parse_callout(text: &str) -> Option<Callout> {
+    if let Some(captures) = CALLOUT_PATTERN_START.captures(&text.trim_end()) {
+        match (captures.name("type"), captures.name("title")) {
+            (Some(callout_type), None) => Callout::build(callout_type.as_str()).ok(),
+            (Some(callout_type), Some(title)) => {
+                Callout::build_with_title(callout_type.as_str(), title.as_str().trim()).ok()
+            }
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+/// Replaces emoji shortcodes with emoji. The ranges of shortcodes that
+/// aren't known are added to `unknown`.
+fn convert_emojis<'a>(
+    input: &'a str,
+    parse_opts: &ParseOptions,
+    unknown: &mut Vec<(Range<usize>, String)>,
+) -> Cow<'a, str> {
+    if !parse_opts.emoji || !input.contains(':') {
+        return Cow::Borrowed(input);
+    }
+
+    let mut acc = String::with_capacity(input.len());
+    let mut parsing_emoji = false;
+    let mut emoji_identifier = String::new();
+    let mut emoji_start = 0;
+    // Where the emoji at the very end of `acc` starts, if there is one, for
+    // a skin tone to apply to.
+    let mut last_emoji = None;
+
+    for (index, c) in input.char_indices() {
+        match (c, parsing_emoji) {
+            (':', false) => {
+                parsing_emoji = true;
+                emoji_start = index;
+            }
+            (':', true) => {
+                let emoji = match parse_opts.custom_emoji.get(&emoji_identifier) {
+                    Some(CustomEmoji::Text(text)) => Some(text.clone()),
+                    // Image emojis in text that can't hold them, like alt
+                    // text, stay as they are.
+                    Some(CustomEmoji::Image(_)) => Some(format!(":{}:", emoji_identifier)),
+                    None => github_emoji(&emoji_identifier)
+                        .map(String::from)
+                        .or_else(|| {
+                            emojis::lookup(&emoji_identifier).map(|emoji| emoji.to_string())
+                        }),
+                };
+
+                match (skin_tone(&emoji_identifier), last_emoji.take()) {
+                    (Some(tone), Some(start)) => {
+                        let toned =
+                            format!("{}{}", acc[start..].trim_end_matches('\u{fe0f}'), tone);
+
+                        // Emojis that don't take a skin tone are left as they are.
+                        if emojis::lookup(&toned).is_some() {
+                            acc.truncate(start);
+                            acc.push_str(&toned);
+                        }
+                    }
+                    _ => {
+                        if let Some(emoji) = emoji {
+                            last_emoji = Some(acc.len());
+                            acc.push_str(&emoji);
+                        } else {
+                            // A skin tone with no emoji before it isn't a typo.
+                            if looks_like_emoji(&emoji_identifier)
+                                && skin_tone(&emoji_identifier).is_none()
+                            {
+                                unknown.push((emoji_start..index + 1, emoji_identifier.clone()));
+                            }
+
+                            acc.push(':');
+                            acc.push_str(&emoji_identifier);
+                            acc.push(':');
+                        }
+                    }
+                }
+
+                parsing_emoji = false;
+                emoji_identifier.truncate(0);
+            }
+            (_, true) => emoji_identifier.push(c),
+            (_, false) => {
+                last_emoji = None;
+                acc.push(c);
+            }
+        }
+    }
+
+    if parsing_emoji {
+        acc.push(':');
+        acc.push_str(&emoji_identifier);
+    }
+
+    Cow::Owned(acc)
+}
+
+/// The ranges of the custom emoji shortcodes in `input` that are images,
+/// and their URLs. Shortcodes are found the same way as in
+/// [`convert_emojis`].
+fn image_emojis<'a>(input: &str, parse_opts: &'a ParseOptions) -> Vec<(Range<usize>, &'a str)> {
+    let mut found = Vec::new();
+
+    if !parse_opts.emoji || !input.contains(':') {
+        return found;
+    }
+
+    let mut start = None;
+
+    for (index, c) in input.char_indices() {
+        if c != ':' {
+            continue;
+        }
+
+        match start.take() {
+            None => start = Some(index),
+            Some(start) => {
+                if let Some(CustomEmoji::Image(url)) =
+                    parse_opts.custom_emoji.get(&input[start + 1..index])
+                {
+                    found.push((start..index + 1, url.as_str()));
+                }
+            }
+        }
+    }
+
+    found
+}
+
+/// GitHub's shortcodes that aren't words, like `:+1:`, and Unicode
+/// stand-ins for GitHub's own shortcodes, which are images on GitHub. These
+/// are looked up before the emojis crate, so they don't depend on its
+/// naming.
+fn github_emoji(identifier: &str) -> Option<&'static str> {
+    match identifier {
+        "+1" | "thumbsup" => Some("👍"),
+        "-1" | "thumbsdown" => Some("👎"),
+        "100" => Some("💯"),
+        "1234" => Some("🔢"),
+        "zero" => Some("0️⃣"),
+        "one" => Some("1️⃣"),
+        "two" => Some("2️⃣"),
+        "three" => Some("3️⃣"),
+        "four" => Some("4️⃣"),
+        "five" => Some("5️⃣"),
+        "six" => Some("6️⃣"),
+        "seven" => Some("7️⃣"),
+        "eight" => Some("8️⃣"),
+        "nine" => Some("9️⃣"),
+        "keycap_ten" => Some("🔟"),
+        "shipit" | "squirrel" => Some("🐿️"),
+        "simple_smile" => Some("🙂"),
+        "rage1" | "rage2" | "rage3" | "rage4" => Some("😡"),
+        "octocat" => Some("🐙"),
+        "trollface" => Some("😈"),
+        "suspect" => Some("🤨"),
+        "bowtie" => Some("🎀"),
+        _ => None,
+    }
+}
+
+/// Whether the text between two colons is meant to be an emoji shortcode,
+/// rather than e.g. part of a time or a URL.
+fn looks_like_emoji(identifier: &str) -> bool {
+    identifier.chars().any(|c| c.is_ascii_alphabetic())
+        && identifier
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+'))
+}
+
+/// The skin tone modifier for shortcodes like `:skin-tone-3:`, numbered
+/// from 2 to 6 as on GitHub and Slack.
+fn skin_tone(identifier: &str) -> Option<char> {
+    match identifier {
+        "skin-tone-2" => Some('\u{1f3fb}'),
+        "skin-tone-3" => Some('\u{1f3fc}'),
+        "skin-tone-4" => Some('\u{1f3fd}'),
+        "skin-tone-5" => Some('\u{1f3fe}'),
+        "skin-tone-6" => Some('\u{1f3ff}'),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+#[allow(
+    clippy::needless_borrow,
+    clippy::field_reassign_with_default,
+    clippy::get_first,
+    clippy::assertions_on_constants
+)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_a_markdown_doc() {
+        let input = indoc! {"
+        # My heading
 
         Some content
 
-        ## Some other heading
+        ## Some other heading
+        "};
+
+        let Markdown {
+            as_html,
+            headings,
+            links: _,
+            ..
+        } = parse(&input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <h1 id=\"my-heading\">My heading</h1>
+                <p>Some content</p>
+                <h2 id=\"some-other-heading\">Some other heading</h2>
+            "}
+        );
+
+        assert_eq!(
+            headings,
+            vec![
+                Heading {
+                    title: "My heading".to_string(),
+                    anchor: "my-heading".to_string(),
+                    level: 1,
+                },
+                Heading {
+                    title: "Some other heading".to_string(),
+                    anchor: "some-other-heading".to_string(),
+                    level: 2,
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn optionally_rewrites_link_root_path() {
+        let input = indoc! {"
+        [an link](/foo/bar)
+        "};
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(&input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"/foo/bar\">an link</a></p>
+            "}
+        );
+
+        let mut options = ParseOptions::default();
+        options.url_root = "/other/root".to_owned();
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(&input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"/other/root/foo/bar\">an link</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn prepends_the_image_root_to_local_images() {
+        let input = indoc! {"
+        [a page](/guide) ![a cat](/cat.jpg) ![a dog](/dog.jpg) ![remote](https://example.com/x.png)
+        "};
+
+        let mut options = ParseOptions {
+            url_root: "/docs".to_owned(),
+            image_root: Some("/static/".to_owned()),
+            ..ParseOptions::default()
+        };
+        options.link_rewrite_rules.insert(
+            "/dog.jpg".to_owned(),
+            "https://cdn.example.com/dog.jpg".into(),
+        );
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"/docs/guide\">a page</a> <img src=\"/static/cat.jpg\" alt=\"a cat\"> <img src=\"https://cdn.example.com/dog.jpg\" alt=\"a dog\"> <img src=\"https://example.com/x.png\" alt=\"remote\"></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn joins_url_root_with_forward_slashes() {
+        assert_eq!(join_url_root("/docs", "/foo/bar"), "/docs/foo/bar");
+        assert_eq!(
+            join_url_root("/docs/v1", "/foo/bar/baz"),
+            "/docs/v1/foo/bar/baz"
+        );
+        assert_eq!(join_url_root("/", "/foo/bar"), "/foo/bar");
+    }
+
+    #[test]
+    fn joins_url_root_with_exactly_one_slash() {
+        let roots = ["", "/", "/docs", "/docs/", "/docs//"];
+        let cases = [
+            ("/guide", "guide"),
+            ("//guide", "guide"),
+            ("/guide/", "guide/"),
+            ("/", ""),
+        ];
+
+        for root in roots {
+            let expected_root = root.trim_end_matches('/');
+
+            for (link, expected_path) in cases {
+                assert_eq!(
+                    join_url_root(root, link),
+                    format!("{}/{}", expected_root, expected_path),
+                    "root {:?}, link {:?}",
+                    root,
+                    link
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn does_not_produce_double_slashes_with_a_trailing_slash_root() {
+        let input = indoc! {"
+        [an link](/guide)
+        "};
+
+        let options = ParseOptions {
+            url_root: "/docs/".to_owned(),
+            ..ParseOptions::default()
+        };
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"/docs/guide\">an link</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn maps_readme_and_index_links_to_directories() {
+        let input = indoc! {"
+        [overview](./README.md) [api](/api/index.md) [setup](guide/Readme.md#setup) [root](README.md)
+        "};
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"./\">overview</a> <a href=\"/api/\">api</a> <a href=\"guide/#setup\">setup</a> <a href=\"./\">root</a></p>
+            "}
+        );
+
+        let options = ParseOptions {
+            url_root: "/docs".to_owned(),
+            ..ParseOptions::default()
+        };
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse("[api](/api/index.md)", Some(options));
+
+        assert_eq!(as_html, "<p><a href=\"/docs/api/\">api</a></p>\n");
+    }
+
+    #[test]
+    fn does_not_rewrite_non_absolute_urls() {
+        let input = indoc! {"
+        [an link](https://www.google.com)
+        "};
+
+        let mut options = ParseOptions::default();
+        options.url_root = "/other/root".to_owned();
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(&input, Some(options));
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"https://www.google.com\">an link</a></p>
+            "}
+        );
+
+        let input = indoc! {"
+        [an link](relative/link)
+        "};
+
+        let mut options = ParseOptions::default();
+        options.url_root = "/other/root".to_owned();
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(&input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"relative/link\">an link</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn rewrites_any_image_that_has_an_explicit_rewrite_mapping() {
+        let input = indoc! {"
+        ![an image](/assets/cat.jpg)
+        "};
+
+        let mut options = ParseOptions::default();
+
+        options.link_rewrite_rules.insert(
+            "/assets/cat.jpg".to_owned(),
+            "https://example.com/cat.jpg".into(),
+        );
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(&input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><img src=\"https://example.com/cat.jpg\" alt=\"an image\"></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn rewrites_any_link_that_has_an_explicit_rewrite_mapping() {
+        let input = indoc! {"
+        [an document](/assets/plans.pdf)
+        "};
+
+        let mut options = ParseOptions::default();
+
+        options.link_rewrite_rules.insert(
+            "/assets/plans.pdf".to_owned(),
+            "https://example.com/plans.pdf".into(),
+        );
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(&input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"https://example.com/plans.pdf\">an document</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn normalizes_urls_before_matching_rewrite_rules() {
+        let input = indoc! {"
+        ![one](./assets/cat.jpg) ![two](/assets//cat.jpg) ![three](assets/cat.jpg)
+        [four](.//old/./page) [five](/old/page/) [six](./other//page)
+        "};
+
+        let mut options = ParseOptions::default();
+        options.link_rewrite_rules.insert(
+            "/assets/cat.jpg".to_owned(),
+            "https://cdn.example.com/cat.jpg".into(),
+        );
+        options
+            .link_rewrite_rules
+            .insert("/old//page".to_owned(), "/new/page".into());
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><img src=\"https://cdn.example.com/cat.jpg\" alt=\"one\"> <img src=\"https://cdn.example.com/cat.jpg\" alt=\"two\"> <img src=\"assets/cat.jpg\" alt=\"three\">
+                <a href=\"/new/page\">four</a> <a href=\"/old/page/\">five</a> <a href=\"./other//page\">six</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn normalizes_urls_before_matching_prefix_rules() {
+        let input = indoc! {"
+        [one](/guides//install) [two](./guides/./setup?step=2#top)
+        "};
+
+        let mut options = ParseOptions::default();
+        options
+            .link_rewrite_rules
+            .insert("/guides/".to_owned(), "/docs/".into());
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"/docs/install\">one</a> <a href=\"/docs/setup?step=2#top\">two</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn optionally_ignores_trailing_slashes_when_matching_rewrite_rules() {
+        let input = indoc! {"
+        [one](/guide/) [two](/guide) [three](/other)
+        "};
+
+        let mut options = ParseOptions {
+            rewrite_rules_ignore_trailing_slash: true,
+            ..ParseOptions::default()
+        };
+        options
+            .link_rewrite_rules
+            .insert("/guide".to_owned(), "/handbook".into());
+        options
+            .link_rewrite_rules
+            .insert("/other/".to_owned(), "/elsewhere/".into());
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"/handbook\">one</a> <a href=\"/handbook\">two</a> <a href=\"/elsewhere/\">three</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn appends_parameters_before_the_fragment() {
+        let input = indoc! {"
+        [deep link](./page#section) [with query](/page?tab=2#section) [same page](#section)
+        "};
+
+        let mut options = ParseOptions::default();
+        options
+            .url_params
+            .insert("base".to_owned(), "123".to_owned());
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"./page?base=123#section\">deep link</a> <a href=\"/page?tab=2&amp;base=123#section\">with query</a> <a href=\"#section\">same page</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn rewrites_reference_style_links_and_images() {
+        let input = indoc! {"
+        [reference][1], [collapsed][], [shortcut] and ![an image][cat]
+
+        [1]: /guide/install
+        [collapsed]: /guide/collapsed
+        [shortcut]: /old/page
+        [cat]: /assets/cat.jpg
+        "};
+
+        let mut options = ParseOptions {
+            url_root: "/docs".to_owned(),
+            ..ParseOptions::default()
+        };
+        options
+            .link_rewrite_rules
+            .insert("/old/page".to_owned(), "/new/page".into());
+        options
+            .url_params
+            .insert("base".to_owned(), "123".to_owned());
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"/docs/guide/install?base=123\">reference</a>, <a href=\"/docs/guide/collapsed?base=123\">collapsed</a>, <a href=\"/new/page?base=123\">shortcut</a> and <img src=\"/docs/assets/cat.jpg\" alt=\"an image\"></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn rewrites_links_matching_a_prefix_rule() {
+        let input = indoc! {"
+        [an document](/assets/docs/plans.pdf)
+
+        ![an image](/assets/cat.jpg)
+        "};
+
+        let mut options = ParseOptions::default();
+
+        options
+            .link_rewrite_rules
+            .insert("/assets/".to_owned(), "https://cdn.example.com/".into());
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"https://cdn.example.com/docs/plans.pdf\">an document</a></p>
+                <p><img src=\"https://cdn.example.com/cat.jpg\" alt=\"an image\"></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn exact_rewrite_rules_take_precedence_over_prefix_rules() {
+        let mut rules = HashMap::new();
+        rules.insert("/assets/".to_owned(), "https://cdn.example.com/".into());
+        rules.insert(
+            "/assets/cat.jpg".to_owned(),
+            "https://example.com/cat.jpg".into(),
+        );
+
+        assert_eq!(
+            find_rewrite_rule("/assets/cat.jpg", &rules, false).map(|(url, _)| url),
+            Some("https://example.com/cat.jpg".to_owned())
+        );
+        assert_eq!(
+            find_rewrite_rule("/assets/dog.jpg", &rules, false).map(|(url, _)| url),
+            Some("https://cdn.example.com/dog.jpg".to_owned())
+        );
+    }
+
+    #[test]
+    fn the_longest_matching_prefix_rule_wins() {
+        let mut rules = HashMap::new();
+        rules.insert("/assets/".to_owned(), "https://cdn.example.com/".into());
+        rules.insert(
+            "/assets/videos/".to_owned(),
+            "https://videos.example.com/".into(),
+        );
+        // Keys without a trailing slash only ever match exactly.
+        rules.insert(
+            "/assets/vid".to_owned(),
+            "https://wrong.example.com/".into(),
+        );
+
+        assert_eq!(
+            find_rewrite_rule("/assets/videos/intro.mp4", &rules, false).map(|(url, _)| url),
+            Some("https://videos.example.com/intro.mp4".to_owned())
+        );
+        assert_eq!(
+            find_rewrite_rule("/assets/images/intro.png", &rules, false).map(|(url, _)| url),
+            Some("https://cdn.example.com/images/intro.png".to_owned())
+        );
+        assert_eq!(
+            find_rewrite_rule("/other/intro.png", &rules, false).map(|(url, _)| url),
+            None
+        );
+    }
+
+    #[test]
+    fn fills_in_placeholders_in_rewrite_rules() {
+        let input = indoc! {"
+        ![an image](/assets/img/cat.jpg)
+
+        [an document](/downloads/plans.pdf)
+        "};
+
+        let mut options = ParseOptions::default();
+
+        options.link_rewrite_rules.insert(
+            "/assets/".to_owned(),
+            "https://cdn.example.com/{path}?v={hash}".into(),
+        );
+        options.link_rewrite_rules.insert(
+            "/downloads/plans.pdf".to_owned(),
+            "https://files.example.com/{filename}?from={url}".into(),
+        );
+        options
+            .url_params
+            .insert("base".to_owned(), "123".to_owned());
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><img src=\"https://cdn.example.com/img/cat.jpg?v=%7Bhash%7D\" alt=\"an image\"></p>
+                <p><a href=\"https://files.example.com/plans.pdf?from=/downloads/plans.pdf\">an document</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn leaves_unknown_placeholders_untouched() {
+        assert_eq!(
+            expand_rewrite_template("/{lang}/{path}/{}", "/guide/intro", "guide/intro"),
+            "/{lang}/guide/intro/{}"
+        );
+    }
+
+    #[test]
+    fn appends_parameters_once_to_local_rewrite_rule_results() {
+        let input = indoc! {"
+        [an page](/old/page)
+        "};
+
+        let mut options = ParseOptions::default();
+
+        options
+            .link_rewrite_rules
+            .insert("/old/".to_owned(), "/new/{path}".into());
+        options
+            .url_params
+            .insert("base".to_owned(), "123".to_owned());
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"/new/page?base=123\">an page</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn optionally_sets_titles_on_rewritten_links() {
+        let input = indoc! {"
+        [an download](/downloads/agent.tar.gz) ![an image](/assets/cat.jpg \"A cat\") [an other](/other \"Other\") [an release](/v2/notes)
+        "};
+
+        let mut options = ParseOptions::default();
+
+        options.link_rewrite_rules.insert(
+            "/downloads/agent.tar.gz".to_owned(),
+            LinkRewrite {
+                url: "https://s3.example.com/agent.tar.gz?sig=abc".to_owned(),
+                title: Some("Expires in 1 hour".to_owned()),
+            },
+        );
+        options.link_rewrite_rules.insert(
+            "/assets/".to_owned(),
+            LinkRewrite {
+                url: "https://cdn.example.com/".to_owned(),
+                title: Some("From the CDN".to_owned()),
+            },
+        );
+        options.regex_rewrite_rules.push(
+            RegexRewriteRule::new(r"^/v(\d+)/", "/versions/$1/")
+                .unwrap()
+                .with_title("An old version"),
+        );
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"https://s3.example.com/agent.tar.gz?sig=abc\" title=\"Expires in 1 hour\">an download</a> <img src=\"https://cdn.example.com/cat.jpg\" alt=\"an image\" title=\"From the CDN\"> <a href=\"/other\" title=\"Other\">an other</a> <a href=\"/versions/2/notes\" title=\"An old version\">an release</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn optionally_applies_the_url_root_after_rewrite_rules() {
+        let input = indoc! {"
+        [moved](/old-location/page) [external](/external) [versioned](/v2/page)
+        "};
+
+        let mut options = ParseOptions {
+            url_root: "/docs".to_owned(),
+            ..ParseOptions::default()
+        };
+        options
+            .link_rewrite_rules
+            .insert("/old-location/page".to_owned(), "/new-location/page".into());
+        options
+            .link_rewrite_rules
+            .insert("/external".to_owned(), "https://example.com/page".into());
+        options
+            .regex_rewrite_rules
+            .push(RegexRewriteRule::new(r"^/v(\d+)/(.*)$", "/versions/$1/$2").unwrap());
+
+        let Markdown { as_html, .. } = parse(input, Some(options.clone()));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"/new-location/page\">moved</a> <a href=\"https://example.com/page\">external</a> <a href=\"/versions/2/page\">versioned</a></p>
+            "}
+        );
+
+        options.url_root_after_rewrite = true;
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"/docs/new-location/page\">moved</a> <a href=\"https://example.com/page\">external</a> <a href=\"/docs/versions/2/page\">versioned</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn rewrites_links_matching_a_regex_rule() {
+        let input = indoc! {"
+        [an api](/v1.2/api/users)
+
+        [an guide](/guide/users)
+        "};
+
+        let mut options = ParseOptions::default();
+
+        options.regex_rewrite_rules.push(
+            RegexRewriteRule::new(
+                r"^/v(?P<version>\d+\.\d+)/api/(.*)$",
+                "https://api.example.com/$version/$2",
+            )
+            .unwrap(),
+        );
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"https://api.example.com/1.2/users\">an api</a></p>
+                <p><a href=\"/guide/users\">an guide</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn exact_rewrite_rules_take_precedence_over_regex_rules() {
+        let input = indoc! {"
+        [an api](/v1.2/api/users)
+        "};
+
+        let mut options = ParseOptions::default();
+
+        options.link_rewrite_rules.insert(
+            "/v1.2/api/users".to_owned(),
+            "https://example.com/users".into(),
+        );
+        options
+            .regex_rewrite_rules
+            .push(RegexRewriteRule::new(r"^/v(\d+\.\d+)/", "https://api.example.com/$1/").unwrap());
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"https://example.com/users\">an api</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_regex_rewrite_rules() {
+        assert!(RegexRewriteRule::new(r"^/v(\d+", "$1").is_err());
+    }
+
+    #[test]
+    fn resolves_links_through_a_link_resolver() {
+        let input = indoc! {"
+        [an page](other-page.md)
+
+        [an other](/guide)
+
+        ![an image](cat.jpg)
+        "};
+
+        let resolver = |url: &str, kind: LinkKind| match (url, kind) {
+            ("other-page.md", LinkKind::Link) => Some("/pages/other-page".to_owned()),
+            ("cat.jpg", LinkKind::Image) => Some("https://cdn.example.com/cat.jpg".to_owned()),
+            _ => None,
+        };
+
+        let mut options = ParseOptions {
+            url_root: "/docs".to_owned(),
+            ..ParseOptions::default()
+        };
+        options.hooks.link_resolver = Some(Arc::new(resolver));
+        options.link_rewrite_rules.insert(
+            "other-page.md".to_owned(),
+            "https://example.com/wrong".into(),
+        );
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"/docs/pages/other-page\">an page</a></p>
+                <p><a href=\"/docs/guide\">an other</a></p>
+                <p><img src=\"https://cdn.example.com/cat.jpg\" alt=\"an image\"></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn appends_parameters_to_the_end_of_urls() {
+        let input = indoc! {"
+        [an link](relative/link)
+        "};
+
+        let mut options = ParseOptions::default();
+
+        options
+            .url_params
+            .insert("base".to_owned(), "123".to_owned());
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(&input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"relative/link?base=123\">an link</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn appends_multiple_parameters_to_the_end_of_urls() {
+        let input = indoc! {"
+        [an link](relative/link)
+        "};
+
+        let mut options = ParseOptions::default();
+
+        options
+            .url_params
+            .insert("bases".to_owned(), "are".to_owned());
+        options
+            .url_params
+            .insert("belong".to_owned(), "tous".to_owned());
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(&input, Some(options));
+
+        assert!(as_html.contains("bases=are"));
+        assert!(as_html.contains("belong=tous"));
+        assert!(as_html.contains("&amp;"));
+    }
+
+    #[test]
+    fn appends_multiple_parameters_to_the_end_of_absolute_urls() {
+        let input = indoc! {"
+        [an link](/absolute/link)
+        "};
+
+        let mut options = ParseOptions::default();
+
+        options
+            .url_params
+            .insert("base".to_owned(), "123".to_owned());
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(&input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"/absolute/link?base=123\">an link</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn merges_parameters_with_an_existing_query_string() {
+        let input = indoc! {"
+        [report](./report?tab=summary)
+
+        [trailing](./report?)
+
+        [existing](./report?base=456)
+        "};
+
+        let mut options = ParseOptions::default();
+
+        options
+            .url_params
+            .insert("base".to_owned(), "123".to_owned());
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"./report?tab=summary&amp;base=123\">report</a></p>
+                <p><a href=\"./report?base=123\">trailing</a></p>
+                <p><a href=\"./report?base=456\">existing</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn optionally_appends_parameters_to_local_image_sources() {
+        let input = indoc! {"
+        ![an image](/assets/cat.jpg)
+
+        ![an remote image](https://example.com/dog.jpg)
+        "};
+
+        let mut options = ParseOptions::default();
+
+        options.url_params.insert("v".to_owned(), "abc".to_owned());
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(input, Some(options.clone()));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><img src=\"/assets/cat.jpg\" alt=\"an image\"></p>
+                <p><img src=\"https://example.com/dog.jpg\" alt=\"an remote image\"></p>
+            "}
+        );
+
+        options.url_params_on_images = true;
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><img src=\"/assets/cat.jpg?v=abc\" alt=\"an image\"></p>
+                <p><img src=\"https://example.com/dog.jpg\" alt=\"an remote image\"></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn does_not_append_params_to_exempt_urls() {
+        let input = indoc! {"
+        [signed](/old/agent.tar.gz)
+
+        [regular](/downloads/other.tar.gz)
+        "};
+
+        let mut options = ParseOptions::default();
+
+        options.link_rewrite_rules.insert(
+            "/old/agent.tar.gz".to_owned(),
+            "/signed/agent.tar.gz?sig=abc".into(),
+        );
+        options
+            .url_params_exempt_prefixes
+            .push("/signed/".to_owned());
+        options
+            .url_params
+            .insert("base".to_owned(), "123".to_owned());
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"/signed/agent.tar.gz?sig=abc\">signed</a></p>
+                <p><a href=\"/downloads/other.tar.gz?base=123\">regular</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn does_not_append_params_to_fragment_only_or_empty_urls() {
+        let input = indoc! {"
+        [jump](#setup) and [nowhere]()
+        "};
+
+        let mut options = ParseOptions::default();
+
+        options
+            .url_params
+            .insert("base".to_owned(), "123".to_owned());
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"#setup\">jump</a> and <a href=\"\">nowhere</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn does_not_append_params_to_urls_with_a_specific_domain() {
+        let input = indoc! {"
+        [an link](http://www.example.com/)
+        "};
+
+        let mut options = ParseOptions::default();
+
+        options
+            .url_params
+            .insert("bases".to_owned(), "are".to_owned());
+        options
+            .url_params
+            .insert("belong".to_owned(), "tous".to_owned());
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(&input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"http://www.example.com/\">an link</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn optionally_opens_external_links_in_a_new_tab() {
+        let input = indoc! {"
+        [remote](https://www.example.com \"Example\") [local](/guide) [anchor](#setup)
+        "};
+
+        let options = ParseOptions {
+            external_links_in_new_tab: true,
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"https://www.example.com\" title=\"Example\" target=\"_blank\" rel=\"noopener noreferrer\">remote</a> <a href=\"/guide\">local</a> <a href=\"#setup\">anchor</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn optionally_marks_external_links_as_nofollow() {
+        let input = indoc! {"
+        [remote](https://www.example.com) [local](/guide)
+        "};
+
+        let mut options = ParseOptions {
+            nofollow_external_links: true,
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options.clone()));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"https://www.example.com\" rel=\"nofollow\">remote</a> <a href=\"/guide\">local</a></p>
+            "}
+        );
+
+        options.external_links_in_new_tab = true;
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"https://www.example.com\" target=\"_blank\" rel=\"noopener noreferrer nofollow\">remote</a> <a href=\"/guide\">local</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn optionally_adds_a_class_to_external_links() {
+        let input = indoc! {"
+        [remote](https://www.example.com) [local](/guide) [anchor](#setup)
+
+        <a href=\"/raw\" class=\"external other\">raw</a>
+        "};
+
+        let options = ParseOptions {
+            external_link_class: Some("external".to_owned()),
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"https://www.example.com\" class=\"external\">remote</a> <a href=\"/guide\">local</a> <a href=\"#setup\">anchor</a></p>
+                <p><a href=\"/raw\" class=\"external\">raw</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn optionally_adds_an_icon_to_external_links() {
+        let input = indoc! {"
+        [remote](https://www.example.com) [local](/guide) [![thumb](/thumb.png)](https://www.example.com/full.png)
+        "};
+
+        let options = ParseOptions {
+            external_link_icon: Some(
+                "<span class=\"external-icon\" aria-hidden=\"true\"></span>".to_owned(),
+            ),
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, links, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"https://www.example.com\">remote<span class=\"external-icon\" aria-hidden=\"true\"></span></a> <a href=\"/guide\">local</a> <a href=\"https://www.example.com/full.png\"><img src=\"/thumb.png\" alt=\"thumb\"></a></p>
+            "}
+        );
+
+        assert_eq!(links[0].title, "remote");
+    }
+
+    #[test]
+    fn strips_target_and_rel_from_links_by_default() {
+        let input = indoc! {"
+        <a href=\"https://www.example.com\" target=\"_blank\" rel=\"opener\">raw</a>
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            "<p><a href=\"https://www.example.com\">raw</a></p>\n"
+        );
+    }
+
+    #[test]
+    fn sanitizes_input() {
+        let input = indoc! {"
+        <script>
+        alert('I break you');
+        </script>
+        "};
+
+        let options = ParseOptions::default();
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(&input, Some(options));
+
+        assert_eq!(as_html, "\n");
+    }
+
+    #[test]
+    fn allows_extra_tags_attributes_and_classes() {
+        let input = indoc! {"
+        <section class=\"terms other\"><my-widget size=\"2\">Widget</my-widget></section>
+
+        On <time datetime=\"2024-01-01\">New Year</time> <span class=\"badge evil\">new</span>
+        <div class=\"wide evil\">Wide</div>
+        <p class=\"anything\">still allowed</p>
+        <script>alert('still removed')</script>
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                Widget
+                <p>On <time>New Year</time> <span class=\"\">new</span></p>
+                <div class=\"\">Wide</div>
+                <p class=\"anything\">still allowed</p>
+
+            "}
+        );
+
+        let options = ParseOptions {
+            extra_allowed_tags: vec![
+                "section".to_string(),
+                "my-widget".to_string(),
+                "script".to_string(),
+            ],
+            extra_tag_attributes: vec![
+                ("my-widget".to_string(), vec!["size".to_string()]),
+                ("time".to_string(), vec!["datetime".to_string()]),
+            ]
+            .into_iter()
+            .collect(),
+            extra_allowed_classes: vec![
+                ("section".to_string(), vec!["terms".to_string()]),
+                ("span".to_string(), vec!["badge".to_string()]),
+                ("div".to_string(), vec!["wide".to_string()]),
+                ("p".to_string(), vec!["note".to_string()]),
+            ]
+            .into_iter()
+            .collect(),
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <section class=\"terms\"><my-widget size=\"2\">Widget</my-widget></section>
+                <p>On <time datetime=\"2024-01-01\">New Year</time> <span class=\"badge\">new</span></p>
+                <div class=\"wide\">Wide</div>
+                <p class=\"anything\">still allowed</p>
+
+            "}
+        );
+    }
+
+    #[test]
+    fn allows_listed_span_classes() {
+        let input = indoc! {"
+        <span class=\"badge\">Beta</span> Added in <span class=\"since evil\">v2.3</span>, see <span class=\"evil\">notes</span>
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            "<p><span class=\"\">Beta</span> Added in <span class=\"\">v2.3</span>, see <span class=\"\">notes</span></p>\n"
+        );
+
+        let options = ParseOptions {
+            extra_allowed_classes: vec![(
+                "span".to_string(),
+                vec!["badge".to_string(), "since".to_string()],
+            )]
+            .into_iter()
+            .collect(),
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            "<p><span class=\"badge\">Beta</span> Added in <span class=\"since\">v2.3</span>, see <span class=\"\">notes</span></p>\n"
+        );
+    }
+
+    #[test]
+    fn keeps_ids_on_allowed_elements() {
+        let input = indoc! {"
+        # Pricing
+
+        <div id=\"pricing-table\">
+
+        | Plan | Price |
+        |------|-------|
+        | Pro  | 10    |
+
+        </div>
+
+        <table id='features'><tr><td>Fast</td></tr></table>
+
+        <div id=\"pricing\"></div>
+
+        <div id=\"not a slug\"></div>
+
+        <span id=\"note\">Note</span>
+
+        See [the table](#pricing-table), [features](#features) and [nothing](#note).
+        "};
+
+        let options = ParseOptions {
+            id_elements: vec!["div".to_string(), "table".to_string()],
+            ..ParseOptions::default()
+        };
+
+        let markdown = parse(input, Some(options));
+
+        assert_eq!(
+            markdown.as_html,
+            indoc! {"
+                <h1 id=\"pricing\">Pricing</h1>
+                <div id=\"pricing-table\">
+                <table><thead><tr><th>Plan</th><th>Price</th></tr></thead><tbody>
+                <tr><td>Pro</td><td>10</td></tr>
+                </tbody></table>
+                </div>
+                <table id=\"features\"><tbody><tr><td>Fast</td></tr></tbody></table>
+                <div id=\"pricing\"></div>
+                <div></div>
+                <p><span>Note</span></p>
+                <p>See <a href=\"#pricing-table\">the table</a>, <a href=\"#features\">features</a> and <a href=\"#note\">nothing</a>.</p>
+            "}
+        );
+        assert_eq!(
+            markdown.element_ids,
+            vec!["pricing-table", "features", "pricing"]
+        );
+        assert_eq!(
+            markdown.anchors(),
+            vec!["pricing", "pricing-table", "features", "pricing"]
+        );
+        assert_eq!(
+            markdown
+                .warnings
+                .iter()
+                .map(|warning| &warning.kind)
+                .collect::<Vec<_>>(),
+            vec![
+                &WarningKind::DuplicateAnchor("pricing".to_string()),
+                &WarningKind::BrokenAnchor("note".to_string()),
+            ]
+        );
+
+        let Markdown {
+            as_html,
+            element_ids,
+            ..
+        } = parse("<div id=\"pricing-table\"></div>", None);
+
+        assert_eq!(as_html, "<div></div>");
+        assert!(element_ids.is_empty());
+    }
+
+    #[test]
+    fn allows_custom_elements() {
+        let input = indoc! {"
+        <doc-callout data-kind=\"warning\" data-id=\"1\" class=\"wide evil\" onclick=\"go()\" hidden>
+
+        Be **careful**.
+
+        </doc-callout>
+
+        <other-element data-kind=\"x\">Text</other-element>
+
+        <div data-kind=\"x\">Div</div>
+        "};
+
+        let options = ParseOptions {
+            custom_elements: vec![
+                (
+                    "doc-callout".to_string(),
+                    CustomElement {
+                        attributes: vec![
+                            "data-*".to_string(),
+                            "hidden".to_string(),
+                            "onclick".to_string(),
+                        ],
+                        classes: vec!["wide".to_string()],
+                    },
+                ),
+                (
+                    "script".to_string(),
+                    CustomElement {
+                        attributes: vec!["on*".to_string()],
+                        classes: Vec::new(),
+                    },
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <doc-callout data-kind=\"warning\" data-id=\"1\" class=\"wide\" hidden=\"\">
+                <p>Be <strong>careful</strong>.</p>
+                </doc-callout>
+                <p>Text</p>
+                <div>Div</div>
+            "}
+        );
+    }
+
+    #[test]
+    fn allows_media_elements_with_local_or_allowed_sources() {
+        let input = indoc! {"
+        <video controls src=\"/media/demo.mp4\" poster=\"/media/demo.png\" onplay=\"go()\">
+        <source src=\"media/demo.webm\" type=\"video/webm\">
+        <track src=\"/media/demo.vtt\" kind=\"captions\" srclang=\"en\">
+        </video>
+
+        <audio controls src=\"https://cdn.example.com/intro.mp3\">
+        </audio>
+
+        <video src=\"https://evil.com/demo.mp4\" poster=\"javascript:alert(1)\">
+        </video>
+        "};
+
+        let options = ParseOptions {
+            url_root: "/docs/".to_string(),
+            media_elements: true,
+            allowed_media_hosts: vec!["cdn.example.com".to_string()],
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <video controls=\"\" src=\"/docs/media/demo.mp4\" poster=\"/docs/media/demo.png\">
+                <source src=\"media/demo.webm\" type=\"video/webm\">
+                <track src=\"/docs/media/demo.vtt\" kind=\"captions\" srclang=\"en\">
+                </video>
+                <audio controls=\"\" src=\"https://cdn.example.com/intro.mp3\">
+                </audio>
+                <video>
+                </video>
+            "}
+        );
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert!(!as_html.contains("<video"));
+        assert!(!as_html.contains("<audio"));
+    }
+
+    #[test]
+    fn allows_picture_elements() {
+        let input = indoc! {"
+        <picture>
+        <source media=\"(min-width: 800px)\" srcset=\"/img/wide.jpg 1x, /img/wide@2x.jpg 2x\" type=\"image/jpeg\">
+        <source srcset=\"/img/small.jpg 1x, javascript:alert(1) 2x\">
+        <img src=\"/img/narrow.jpg\" alt=\"A cat\" width=\"300\" onerror=\"go()\">
+        </picture>
+        "};
+
+        let options = ParseOptions {
+            picture_elements: true,
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <picture>
+                <source media=\"(min-width: 800px)\" srcset=\"/img/wide.jpg 1x, /img/wide@2x.jpg 2x\" type=\"image/jpeg\">
+                <source>
+                <img src=\"/img/narrow.jpg\" alt=\"A cat\" width=\"300\">
+                </picture>
+            "}
+        );
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+
+
+
+                <img src=\"/img/narrow.jpg\" alt=\"A cat\" width=\"300\">
+
+            "}
+        );
+    }
+
+    #[test]
+    fn keeps_table_column_alignment() {
+        let input = indoc! {"
+        | Name | Status | Price |
+        |:-----|:------:|------:|
+        | Pro  | Active | 10    |
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <table><thead><tr><th align=\"left\">Name</th><th align=\"center\">Status</th><th align=\"right\">Price</th></tr></thead><tbody>
+                <tr><td align=\"left\">Pro</td><td align=\"center\">Active</td><td align=\"right\">10</td></tr>
+                </tbody></table>
+            "}
+        );
+    }
+
+    #[test]
+    fn allows_inline_text_elements() {
+        let input = indoc! {"
+        Press <kbd>Ctrl</kbd>+<kbd>C</kbd> to copy<sup>1</sup>.
+
+        | Formula | Note |
+        |---------|------|
+        | H<sub>2</sub>O | <mark class=\"new\" onclick=\"go()\">New</mark> |
+
+        * <kbd>Esc</kbd> closes, see<sup>2</sup>
+        * CO<sub>2</sub> is <mark>highlighted</mark>
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p>Press <kbd>Ctrl</kbd>+<kbd>C</kbd> to copy<sup>1</sup>.</p>
+                <table><thead><tr><th>Formula</th><th>Note</th></tr></thead><tbody>
+                <tr><td>H<sub>2</sub>O</td><td><mark class=\"new\">New</mark></td></tr>
+                </tbody></table>
+                <ul>
+                <li><kbd>Esc</kbd> closes, see<sup>2</sup></li>
+                <li>CO<sub>2</sub> is <mark>highlighted</mark></li>
+                </ul>
+            "}
+        );
+    }
+
+    #[test]
+    fn allows_details_blocks() {
+        let input = indoc! {"
+        <details open onclick=\"go()\">
+        <summary>Advanced options</summary>
+
+        * One
+        * Two
+
+        ```rust
+        let a = 1;
+        ```
+
+        </details>
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <details open=\"\">
+                <summary>Advanced options</summary>
+                <ul>
+                <li>One</li>
+                <li>Two</li>
+                </ul>
+                <pre><code class=\"language-rust\">let a = 1;
+                </code></pre>
+                </details>
+            "}
+        );
+
+        let options = ParseOptions {
+            allow_details: false,
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+
+                Advanced options
+                <ul>
+                <li>One</li>
+                <li>Two</li>
+                </ul>
+                <pre><code class=\"language-rust\">let a = 1;
+                </code></pre>
+
+            "}
+        );
+    }
+
+    #[test]
+    fn allows_iframes_from_allowed_hosts() {
+        let input = indoc! {"
+        <iframe src=\"https://www.youtube.com/embed/abc\" width=\"560\" height=\"315\" allow=\"autoplay\" allowfullscreen onload=\"go()\"></iframe>
+
+        <iframe src=\"https://www.loom.com/embed/def\"></iframe>
+        "};
+
+        let options = ParseOptions {
+            allowed_iframe_hosts: vec!["youtube.com".to_string(), "www.loom.com".to_string()],
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <iframe src=\"https://www.youtube.com/embed/abc\" width=\"560\" height=\"315\" allow=\"autoplay\" allowfullscreen=\"\"></iframe>
+                <iframe src=\"https://www.loom.com/embed/def\"></iframe>
+            "}
+        );
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(as_html, "\n\n");
+    }
+
+    #[test]
+    fn removes_iframes_from_other_hosts() {
+        let input = indoc! {"
+        <iframe src=\"https://youtube.com.evil.com/embed/abc\"></iframe>
+
+        <iframe src=\"https://evil.com/?youtube.com\">fallback</iframe>
+
+        <iframe src=\"https://notyoutube.com/embed/abc\"></iframe>
+
+        <iframe src=\"javascript:alert(1)//youtube.com\"></iframe>
+
+        <iframe src=\"/embed/abc\"></iframe>
+
+        Text
+        "};
+
+        let options = ParseOptions {
+            allowed_iframe_hosts: vec!["youtube.com".to_string()],
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(as_html, "\n\n\n\n\n<p>Text</p>\n");
+    }
+
+    #[test]
+    fn can_skip_sanitizing_trusted_content() {
+        let input = indoc! {"
+        # Widgets
+
+        <my-widget onclick=\"go()\">[Docs](/docs)</my-widget>
+
+        <script>track();</script>
+        "};
+
+        let options = ParseOptions {
+            sanitize: false,
+            ..ParseOptions::default()
+        };
+
+        let raw = parse(input, Some(options));
+        let clean = parse(input, None);
+
+        assert_eq!(
+            raw.as_html,
+            indoc! {"
+                <h1 id=\"widgets\">Widgets</h1>
+                <p><my-widget onclick=\"go()\"><a href=\"/docs\">Docs</a></my-widget></p>
+                <script>track();</script>
+            "}
+        );
+        assert_eq!(
+            clean.as_html,
+            indoc! {"
+                <h1 id=\"widgets\">Widgets</h1>
+                <p><a href=\"/docs\">Docs</a></p>
+
+            "}
+        );
+        assert_eq!(raw.headings, clean.headings);
+        assert_eq!(raw.links, clean.links);
+    }
+
+    #[test]
+    fn can_keep_html_comments() {
+        let input = indoc! {"
+        <!-- ad-slot -->
+
+        Text <!-- note to self --> here
+
+        <span title=\"<!--\">x</span><!-- exclude-from-pdf-start -->
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+
+                <p>Text  here</p>
+                <p><span title=\"<!--\">x</span></p>
+            "}
+        );
+
+        let options = ParseOptions {
+            html_comments: HtmlComments::Keep,
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <!-- ad-slot -->
+                <p>Text <!-- note to self --> here</p>
+                <p><span title=\"<!--\">x</span><!-- exclude-from-pdf-start --></p>
+            "}
+        );
+
+        let options = ParseOptions {
+            html_comments: HtmlComments::KeepWithPrefix("exclude-from-pdf".to_string()),
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+
+                <p>Text  here</p>
+                <p><span title=\"<!--\">x</span><!-- exclude-from-pdf-start --></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn comments_cannot_smuggle_markup() {
+        let input = indoc! {"
+        <!--><script>alert(1)</script>-->
+
+        <!-- --!><img src=x onerror=alert(1)> -->
+
+        <!-- a -- b --><!--->x<!--->
+        "};
+
+        let options = ParseOptions {
+            html_comments: HtmlComments::Keep,
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <!---->--&gt;
+                <!-- --><img src=\"x\"> --&gt;
+                <!-- a -- b --><!---->x<!---->
+            "}
+        );
+    }
+
+    #[test]
+    fn allows_configured_url_schemes() {
+        let input = indoc! {"
+        [Page](doctave://project/page) [Mail](mailto:hi@example.com) [Site](https://example.com) [Script](javascript:alert(1))
+
+        ![Logo](ftp://example.com/logo.png)
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a>Page</a> <a href=\"mailto:hi@example.com\">Mail</a> <a href=\"https://example.com\">Site</a> <a>Script</a></p>
+                <p><img src=\"ftp://example.com/logo.png\" alt=\"Logo\"></p>
+            "}
+        );
+
+        let options = ParseOptions {
+            url_schemes: vec!["https", "Doctave", "javascript"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"doctave://project/page\">Page</a> <a>Mail</a> <a href=\"https://example.com\">Site</a> <a>Script</a></p>
+                <p><img alt=\"Logo\"></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn can_keep_the_raw_html() {
+        let input = indoc! {"
+        <my-widget onclick=\"go()\">Widget</my-widget>
+        "};
+
+        let options = ParseOptions {
+            keep_raw_html: true,
+            ..ParseOptions::default()
+        };
+
+        let Markdown {
+            as_html, raw_html, ..
+        } = parse(input, Some(options));
+
+        assert_eq!(as_html, "<p>Widget</p>\n");
+        assert_eq!(
+            raw_html.as_deref(),
+            Some("<p><my-widget onclick=\"go()\">Widget</my-widget></p>\n")
+        );
+
+        assert_eq!(parse(input, None).raw_html, None);
+    }
+
+    #[test]
+    fn sanitizer_hook_can_change_the_sanitizer() {
+        struct SecureLinks;
+
+        impl SanitizerHook for SecureLinks {
+            fn configure(&self, builder: &mut ammonia::Builder<'_>) {
+                builder.add_tags(&["my-widget"]).set_tag_attribute_value(
+                    "a",
+                    "data-source",
+                    "docs",
+                );
+            }
+
+            fn filter_attribute<'u>(
+                &self,
+                element: &str,
+                attribute: &str,
+                value: &'u str,
+            ) -> Option<Cow<'u, str>> {
+                match (element, attribute) {
+                    ("a", "href") if value.starts_with("http://") => {
+                        Some(value.replacen("http://", "https://", 1).into())
+                    }
+                    _ => Some(value.into()),
+                }
+            }
+        }
+
+        let input = indoc! {"
+        [Docs](http://example.com/docs) <my-widget onclick=\"go()\">Widget</my-widget>
+        "};
+
+        let options = ParseOptions {
+            hooks: Hooks {
+                sanitizer: Some(Arc::new(SecureLinks)),
+                ..Hooks::default()
+            },
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            "<p><a href=\"https://example.com/docs\" data-source=\"docs\">Docs</a> <my-widget>Widget</my-widget></p>\n"
+        );
+
+        let hook = |builder: &mut ammonia::Builder<'_>| {
+            builder.rm_tags(&["strong"]);
+        };
+
+        let options = ParseOptions {
+            hooks: Hooks {
+                sanitizer: Some(Arc::new(hook)),
+                ..Hooks::default()
+            },
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse("**Bold**", Some(options));
+
+        assert_eq!(as_html, "<p>Bold</p>\n");
+    }
+
+    #[test]
+    fn allows_mermaid_blocks() {
+        let input = indoc! {"
+        ```mermaid
+        graph TD;
+            A-->B;
+            A-->C;
+        ```
+        "};
+
+        let options = ParseOptions::default();
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(&input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+        <pre class=\"mermaid\">graph TD;
+            A--&gt;B;
+            A--&gt;C;
+        </pre>
+        "}
+        );
+    }
+
+    #[test]
+    fn keeps_mermaid_source_intact() {
+        let input = indoc! {"
+        ```mermaid
+        graph LR;
+            A[\"Fish & Chips\"]-->|yes|B[Line one<br/>line two];
+        ```
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        // The browser decodes the text content of the `<pre>` back to the
+        // original source for mermaid to read.
+        assert_eq!(
+            as_html,
+            indoc! {"
+        <pre class=\"mermaid\">graph LR;
+            A[\"Fish &amp; Chips\"]--&gt;|yes|B[Line one&lt;br/&gt;line two];
+        </pre>
+        "}
+        );
+    }
+
+    #[test]
+    fn renders_configured_diagram_languages() {
+        let input = indoc! {"
+        ```plantuml
+        Alice -> Bob
+        ```
+
+        ```dot
+        a -> b
+        ```
+
+        ```mermaid
+        A-->B
+        ```
+        "};
+
+        let options = ParseOptions {
+            diagram_languages: vec![
+                ("plantuml".to_string(), "plantuml".to_string()),
+                ("dot".to_string(), "graphviz".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+            ..ParseOptions::default()
+        };
+
+        let Markdown {
+            as_html,
+            code_blocks,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <pre class=\"plantuml\">Alice -&gt; Bob
+                </pre>
+                <pre class=\"graphviz\">a -&gt; b
+                </pre>
+                <pre><code class=\"language-mermaid\">A--&gt;B
+                </code></pre>
+            "}
+        );
+        assert!(code_blocks[0].is_diagram);
+        assert!(!code_blocks[2].is_diagram);
+
+        let options = ParseOptions {
+            diagram_languages: HashMap::new(),
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse("```mermaid\nA-->B\n```\n", Some(options));
+
+        assert_eq!(
+            as_html,
+            "<pre><code class=\"language-mermaid\">A--&gt;B\n</code></pre>\n"
+        );
+    }
+
+    #[test]
+    fn nests_diagrams_in_lists_and_quotes() {
+        let input = indoc! {"
+        1. Step one
+
+           ```mermaid
+           A-->B
+           ```
+
+        2. Step two
+
+        > Quote
+        >
+        > ```mermaid
+        > C-->D
+        > ```
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <ol>
+                <li>
+                <p>Step one</p>
+                <pre class=\"mermaid\">A--&gt;B
+                </pre>
+                </li>
+                <li>
+                <p>Step two</p>
+                </li>
+                </ol>
+                <blockquote>
+                <p>Quote</p>
+                <pre class=\"mermaid\">C--&gt;D
+                </pre>
+                </blockquote>
+            "}
+        );
+    }
+
+    #[test]
+    fn adds_the_mermaid_init_directive() {
+        let input = indoc! {"
+        ```mermaid
+        A-->B
+        ```
+
+        ```mermaid
+        %%{init: {\"theme\": \"dark\"}}%%
+        C-->D
+        ```
+        "};
+
+        let options = ParseOptions {
+            mermaid_init: Some("{\"theme\": \"<forest>\"}".to_string()),
+            ..ParseOptions::default()
+        };
+
+        let Markdown {
+            as_html,
+            code_blocks,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <pre class=\"mermaid\">%%{init: {\"theme\": \"&lt;forest&gt;\"}}%%
+                A--&gt;B
+                </pre>
+                <pre class=\"mermaid\">%%{init: {\"theme\": \"dark\"}}%%
+                C--&gt;D
+                </pre>
+            "}
+        );
+        assert_eq!(code_blocks[0].content, "A-->B\n");
+    }
+
+    #[test]
+    fn allows_code_blocks() {
+        let input = indoc! {"
+        ```ruby
+        1 + 1
+        ```
+        "};
+
+        let options = ParseOptions::default();
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links: _,
+            ..
+        } = parse(&input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+        <pre><code class=\"language-ruby\">1 + 1
+        </code></pre>
+ "}
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "syntax-highlighting")]
+    fn highlights_code_blocks_with_classes() {
+        let input = indoc! {"
+        ```rust
+        let x = 1;
+        ```
+
+        ```nosuchlang
+        a < b
+        ```
+
+        <span class=\"evil hl-ok\">x</span>
+        "};
+
+        let options = ParseOptions {
+            syntax_highlighting: Some(SyntaxHighlighting::Classes {
+                prefix: "hl-".to_string(),
+            }),
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <pre><code class=\"language-rust\"><span class=\"hl-source hl-rust\"><span class=\"hl-storage hl-type hl-rust\">let</span> x <span class=\"hl-keyword hl-operator hl-rust\">=</span> <span class=\"hl-constant hl-numeric hl-integer hl-decimal hl-rust\">1</span><span class=\"hl-punctuation hl-terminator hl-rust\">;</span>
+                </span></code></pre>
+                <pre><code class=\"language-nosuchlang\">a &lt; b
+                </code></pre>
+                <p><span class=\"hl-ok\">x</span></p>
+            "}
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "syntax-highlighting")]
+    fn highlights_code_blocks_with_a_theme() {
+        let input = indoc! {"
+        ```rust
+        let x = 1;
+        ```
+        "};
+
+        let options = ParseOptions {
+            syntax_highlighting: Some(SyntaxHighlighting::Theme("InspiredGitHub".to_string())),
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <pre><code class=\"language-rust\"><span style=\"font-weight:bold;color:#a71d5d;\">let</span><span style=\"color:#323232;\"> x </span><span style=\"font-weight:bold;color:#a71d5d;\">= </span><span style=\"color:#0086b3;\">1</span><span style=\"color:#323232;\">;
+                </span></code></pre>
+            "}
+        );
+    }
+
+    #[test]
+    fn numbers_the_lines_of_non_ascii_code() {
+        let input = indoc! {"
+        ```text linenos
+        é
+        日本語 <b>
+        🦀
+        ```
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <pre><code class=\"language-text\"><span class=\"line\" data-line=\"1\">é</span>
+                <span class=\"line\" data-line=\"2\">日本語 &lt;b&gt;</span>
+                <span class=\"line\" data-line=\"3\">🦀</span>
+                </code></pre>
+            "}
+        );
+    }
+
+    #[test]
+    fn numbers_the_lines_of_code_blocks() {
+        let input = indoc! {"
+        ```toml linenos
+        [server]
+
+        host = \"<localhost>\"
+        ```
+
+        ```toml
+        port = 8080
+        ```
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <pre><code class=\"language-toml\"><span class=\"line\" data-line=\"1\">[server]</span>
+                <span class=\"line\" data-line=\"2\"></span>
+                <span class=\"line\" data-line=\"3\">host = \"&lt;localhost&gt;\"</span>
+                </code></pre>
+                <pre><code class=\"language-toml\">port = 8080
+                </code></pre>
+            "}
+        );
+
+        let options = ParseOptions {
+            code_line_numbers: true,
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse("    indented\n", Some(options));
+
+        assert_eq!(
+            as_html,
+            "<pre><code class=\"code-indented\"><span class=\"line\" data-line=\"1\">indented</span>\n</code></pre>\n"
+        );
+    }
+
+    #[test]
+    fn parses_fence_info_strings() {
+        assert_eq!(
+            FenceInfo::parse(r#"rust {3, 5-7} title="Getting started.rs" linenos"#),
+            FenceInfo {
+                language: "rust".to_string(),
+                highlighted_lines: vec![3..=3, 5..=7],
+                attributes: vec![("title".to_string(), "Getting started.rs".to_string())],
+                flags: vec!["linenos".to_string()],
+            }
+        );
+        assert_eq!(FenceInfo::parse("mermaid{1}").language, "mermaid");
+        assert_eq!(FenceInfo::parse("").language, "");
+        assert_eq!(
+            FenceInfo::parse("js {2,x,4-}").highlighted_lines,
+            vec![2..=2]
+        );
+    }
+
+    #[test]
+    fn highlights_lines_listed_in_the_fence() {
+        let input = indoc! {"
+        ```js {1,3-4,99}
+        one
+        two
+        three
+        four
+        ```
+
+        ```mermaid {1}
+        A-->B
+        ```
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <pre><code class=\"language-js\"><span class=\"line hl-line\">one</span>
+                <span class=\"line\">two</span>
+                <span class=\"line hl-line\">three</span>
+                <span class=\"line hl-line\">four</span>
+                </code></pre>
+                <pre class=\"mermaid\">A--&gt;B
+                </pre>
+        "}
+        );
+    }
+
+    #[test]
+    fn marks_added_and_removed_lines_in_diffs() {
+        let input = indoc! {"
+        ```diff
+        @@ -1,2 +1,2 @@
+         context
+        -old <b>
+        +new
+        +
+        ```
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <pre><code class=\"language-diff\"><span class=\"diff-hunk\">@@ -1,2 +1,2 @@</span>
+                 context
+                <span class=\"diff-del\">-old &lt;b&gt;</span>
+                <span class=\"diff-add\">+new</span>
+                <span class=\"diff-add\">+</span>
+                </code></pre>
+            "}
+        );
+    }
+
+    #[test]
+    fn marks_file_headers_in_diffs() {
+        let input = indoc! {"
+        ```diff
+        --- a/src/lib.rs
+        +++ b/src/lib.rs
+        @@ -1 +1 @@
+        -old
+        +new
+        ```
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <pre><code class=\"language-diff\"><span class=\"diff-header\">--- a/src/lib.rs</span>
+                <span class=\"diff-header\">+++ b/src/lib.rs</span>
+                <span class=\"diff-hunk\">@@ -1 +1 @@</span>
+                <span class=\"diff-del\">-old</span>
+                <span class=\"diff-add\">+new</span>
+                </code></pre>
+            "}
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "syntax-highlighting")]
+    fn highlights_the_code_inside_diffs() {
+        let input = indoc! {"
+        ```diff-rust
+        -let x = 1;
+        +let x = 2;
+        ```
+        "};
+
+        let options = ParseOptions {
+            syntax_highlighting: Some(SyntaxHighlighting::Classes {
+                prefix: "hl-".to_string(),
+            }),
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <pre><code class=\"language-diff-rust\"><span class=\"diff-del\">-<span class=\"hl-source hl-rust\"><span class=\"hl-storage hl-type hl-rust\">let</span> x <span class=\"hl-keyword hl-operator hl-rust\">=</span> <span class=\"hl-constant hl-numeric hl-integer hl-decimal hl-rust\">1</span><span class=\"hl-punctuation hl-terminator hl-rust\">;</span></span></span>
+                <span class=\"diff-add\">+<span class=\"hl-source hl-rust\"><span class=\"hl-storage hl-type hl-rust\">let</span> x <span class=\"hl-keyword hl-operator hl-rust\">=</span> <span class=\"hl-constant hl-numeric hl-integer hl-decimal hl-rust\">2</span><span class=\"hl-punctuation hl-terminator hl-rust\">;</span></span></span>
+                </code></pre>
+            "}
+        );
+    }
+
+    #[test]
+    fn renders_csv_code_blocks_as_tables() {
+        let input = indoc! {"
+        ```csv
+        Plan, Price
+        Free,\"$0, forever\"
+        Team, <ask>
+        ```
+
+        ```tsv noheader
+        a\tb
+        ```
+
+        ```csv
+        a,b
+        c
+        ```
+        "};
+
+        let Markdown {
+            as_html, warnings, ..
+        } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <table><thead><tr><th>Plan</th><th>Price</th></tr></thead><tbody>
+                <tr><td>Free</td><td>$0, forever</td></tr>
+                <tr><td>Team</td><td>&lt;ask&gt;</td></tr>
+                </tbody></table>
+                <table><tbody>
+                <tr><td>a</td><td>b</td></tr>
+                </tbody></table>
+                <pre><code class=\"language-csv\">a,b
+                c
+                </code></pre>
+            "}
+        );
+        assert_eq!(warnings.len(), 1);
+        assert!(matches!(warnings[0].kind, WarningKind::InvalidCsv(_)));
+        assert_eq!(&input[warnings[0].range.clone()], "```csv\na,b\nc\n```");
+    }
+
+    #[test]
+    fn groups_consecutive_tabbed_code_blocks() {
+        let input = indoc! {"
+        ```rust tab=\"Rust\"
+        let x = 1;
+        ```
+        ```python tab=\"<Python>\"
+        x = 1
+        ```
+
+        Between
+
+        ```sh tab=Shell
+        x=1
+        ```
+
+        ```sh
+        echo $x
+        ```
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <div class=\"code-tabs\"><div class=\"code-tab-labels\"><span class=\"code-tab-label\" data-tab=\"Rust\">Rust</span><span class=\"code-tab-label\" data-tab=\"<Python>\">&lt;Python&gt;</span></div>
+                <div class=\"code-tab-panel\" data-tab=\"Rust\">
+                <pre><code class=\"language-rust\">let x = 1;
+                </code></pre>
+                </div>
+                <div class=\"code-tab-panel\" data-tab=\"<Python>\">
+                <pre><code class=\"language-python\">x = 1
+                </code></pre>
+                </div>
+                </div>
+                <p>Between</p>
+                <div class=\"code-tabs\"><div class=\"code-tab-labels\"><span class=\"code-tab-label\" data-tab=\"Shell\">Shell</span></div>
+                <div class=\"code-tab-panel\" data-tab=\"Shell\">
+                <pre><code class=\"language-sh\">x=1
+                </code></pre>
+                </div>
+                </div>
+                <pre><code class=\"language-sh\">echo $x
+                </code></pre>
+            "}
+        );
+    }
+
+    #[test]
+    fn applies_the_default_language_to_untagged_code_blocks() {
+        let input = indoc! {"
+        ```
+        plain
+        ```
+
+        ```rust
+        fn main() {}
+        ```
+
+            indented
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <pre><code>plain
+                </code></pre>
+                <pre><code class=\"language-rust\">fn main() {}
+                </code></pre>
+                <pre><code class=\"code-indented\">indented
+                </code></pre>
+            "}
+        );
+
+        let options = ParseOptions {
+            default_code_language: Some("text".to_string()),
+            ..ParseOptions::default()
+        };
+
+        let Markdown {
+            as_html,
+            code_blocks,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <pre><code class=\"language-text\">plain
+                </code></pre>
+                <pre><code class=\"language-rust\">fn main() {}
+                </code></pre>
+                <pre><code class=\"language-text code-indented\">indented
+                </code></pre>
+            "}
+        );
+
+        assert_eq!(code_blocks[0].lang, Some("".to_string()));
+        assert_eq!(code_blocks[2].lang, None);
+    }
+
+    #[test]
+    fn parses_attributes_in_the_fence_info_string() {
+        let input = indoc! {"
+        ```bash {cwd=/srv, 2-3} run=false title=\"Set up, then run\" linenos
+        cd app
+        make
+        ./app
+        ```
+        "};
+
+        let Markdown {
+            as_html,
+            code_blocks,
+            ..
+        } = parse(input, None);
+
+        assert_eq!(
+            code_blocks[0].info,
+            "bash {cwd=/srv, 2-3} run=false title=\"Set up, then run\" linenos"
+        );
+        assert_eq!(code_blocks[0].lang, Some("bash".to_string()));
+        assert_eq!(
+            code_blocks[0].attributes,
+            vec![
+                ("cwd".to_string(), "/srv".to_string()),
+                ("run".to_string(), "false".to_string()),
+                ("title".to_string(), "Set up, then run".to_string()),
+            ]
+            .into_iter()
+            .collect()
+        );
+        assert_eq!(code_blocks[0].flags, vec!["linenos".to_string()]);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <div class=\"code-block\"><div class=\"code-title\">Set up, then run</div>
+                <pre><code class=\"language-bash\"><span class=\"line\" data-line=\"1\">cd app</span>
+                <span class=\"line hl-line\" data-line=\"2\">make</span>
+                <span class=\"line hl-line\" data-line=\"3\">./app</span>
+                </code></pre>
+                </div>
+            "}
+        );
+    }
+
+    #[test]
+    fn expands_tabs_in_code_blocks() {
+        let input = "```\na\tb\nabcd\tc\n\td\n```\n\n```mermaid\nA\t-->B\n```\n";
+
+        let options = ParseOptions {
+            code_tab_width: Some(4),
+            ..ParseOptions::default()
+        };
+
+        let Markdown {
+            as_html,
+            code_blocks,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <pre><code>a   b
+                abcd    c
+                    d
+                </code></pre>
+                <pre class=\"mermaid\">A\t--&gt;B
+                </pre>
+        "}
+        );
+        assert_eq!(code_blocks[0].content, "a\tb\nabcd\tc\n\td\n");
+    }
+
+    #[test]
+    fn adds_a_class_to_inline_code() {
+        let input = indoc! {"
+        # The `<main>` function
+
+        Call [`run()`](/run) with `a < b` and ![`x`](/x.png).
+
+        ```rust
+        main();
+        ```
+        "};
+
+        let options = ParseOptions {
+            inline_code_class: Some("inline-code".to_string()),
+            ..ParseOptions::default()
+        };
+
+        let Markdown {
+            as_html, headings, ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <h1 id=\"the-<main>-function\">The <code class=\"inline-code\">&lt;main&gt;</code> function</h1>
+                <p>Call <a href=\"/run\"><code class=\"inline-code\">run()</code></a> with <code class=\"inline-code\">a &lt; b</code> and <img src=\"/x.png\" alt=\"x\">.</p>
+                <pre><code class=\"language-rust\">main();
+                </code></pre>
+            "}
+        );
+        assert_eq!(headings[0].title, "The <main> function");
+    }
+
+    #[test]
+    fn marks_prompts_and_output_in_shell_sessions() {
+        let input = indoc! {"
+        ```console
+        $ echo \"<hi>\"
+        <hi>
+        $
+        ```
+
+        ```bash
+        echo hi
+        ```
+        "};
+
+        let options = ParseOptions {
+            shell_prompts: true,
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <pre><code class=\"language-console\"><span class=\"prompt\">$ </span>echo \"&lt;hi&gt;\"
+                <span class=\"output\">&lt;hi&gt;</span>
+                <span class=\"prompt\">$</span>
+                </code></pre>
+                <pre><code class=\"language-bash\">echo hi
+                </code></pre>
+            "}
+        );
+    }
+
+    #[test]
+    fn maps_language_aliases() {
+        let input = indoc! {"
+        ```js {1}
+        let x = 1;
+        ```
+
+        ```mmd
+        A-->B
+        ```
+
+        ```python
+        x = 1
+        ```
+        "};
+
+        let options = ParseOptions {
+            language_aliases: vec![
+                ("js".to_string(), "javascript".to_string()),
+                ("mmd".to_string(), "mermaid".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+            ..ParseOptions::default()
+        };
+
+        let Markdown {
+            as_html,
+            code_blocks,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <pre><code class=\"language-javascript\"><span class=\"line hl-line\">let x = 1;</span>
+                </code></pre>
+                <pre class=\"mermaid\">A--&gt;B
+                </pre>
+                <pre><code class=\"language-python\">x = 1
+                </code></pre>
+            "}
+        );
+        assert_eq!(code_blocks[0].lang, Some("js".to_string()));
+        assert_eq!(code_blocks[0].info, "js {1}");
+    }
+
+    #[test]
+    fn embeds_files_in_code_blocks() {
+        let input = indoc! {"
+        ```rust src=\"examples/hello.rs\" lines=\"2-3\"
+        ```
+
+        ```rust src=\"examples/hello.rs\" lines=\"3-9\"
+        ```
+
+        ```rust src=\"missing.rs\"
+        old
+        ```
+        "};
+
+        let loader = |path: &str| match path {
+            "examples/hello.rs" => {
+                Some("// Hello\nfn main() {\n    println!(\"<hi>\");\n}".to_string())
+            }
+            _ => None,
+        };
+
+        let options = ParseOptions {
+            hooks: Hooks {
+                file_loader: Some(Arc::new(loader)),
+                ..Hooks::default()
+            },
+            ..ParseOptions::default()
+        };
+
+        let Markdown {
+            as_html,
+            code_blocks,
+            warnings,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <pre><code class=\"language-rust\">fn main() {
+                    println!(\"&lt;hi&gt;\");
+                </code></pre>
+                <pre><code class=\"language-rust\">Could not embed lines 3-9 of \"examples/hello.rs\"
+                </code></pre>
+                <pre><code class=\"language-rust\">Could not embed \"missing.rs\"
+                </code></pre>
+            "}
+        );
+        assert_eq!(
+            code_blocks[0].content,
+            "fn main() {\n    println!(\"<hi>\");\n"
+        );
+        assert_eq!(
+            warnings.iter().map(|w| w.kind.clone()).collect::<Vec<_>>(),
+            vec![
+                WarningKind::InvalidEmbedLines("3-9".to_string()),
+                WarningKind::MissingEmbed("missing.rs".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn renders_a_title_above_code_blocks() {
+        let input = indoc! {"
+        ```yaml title=\"<doctave>.yaml\"
+        title: Docs
+        ```
+
+        ```sh filename=install.sh
+        make
+        ```
+
+        ```yaml
+        plain: true
+        ```
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <div class=\"code-block\"><div class=\"code-title\">&lt;doctave&gt;.yaml</div>
+                <pre><code class=\"language-yaml\">title: Docs
+                </code></pre>
+                </div>
+                <div class=\"code-block\"><div class=\"code-title\">install.sh</div>
+                <pre><code class=\"language-sh\">make
+                </code></pre>
+                </div>
+                <pre><code class=\"language-yaml\">plain: true
+                </code></pre>
+            "}
+        );
+    }
+
+    #[test]
+    fn wraps_fenced_code_blocks_in_a_container() {
+        let input = indoc! {"
+        ```rust title=main.rs
+        fn main() {}
+        ```
+
+        ```
+        plain
+        ```
+
+        ```mermaid
+        A-->B
+        ```
+        "};
+
+        let options = ParseOptions {
+            code_container_class: Some("code-container".to_string()),
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <div class=\"code-container\" data-lang=\"rust\"><div class=\"code-block\"><div class=\"code-title\">main.rs</div>
+                <pre><code class=\"language-rust\">fn main() {}
+                </code></pre>
+                </div></div>
+                <div class=\"code-container\">
+                <pre><code>plain
+                </code></pre>
+                </div>
+                <pre class=\"mermaid\">A--&gt;B
+                </pre>
+        "}
+        );
+    }
+
+    #[test]
+    fn gathers_a_list_of_code_blocks() {
+        let input = indoc! {"
+        ```rust title=\"main.rs\"
+        let x = a < b && c;
+        ```
+
+        ```mermaid
+        A-->B
+        ```
+
+            indented :smile:
+        "};
+
+        let Markdown { code_blocks, .. } = parse(input, None);
+
+        assert_eq!(
+            code_blocks,
+            vec![
+                CodeBlock {
+                    lang: Some("rust".to_string()),
+                    info: "rust title=\"main.rs\"".to_string(),
+                    attributes: vec![("title".to_string(), "main.rs".to_string())]
+                        .into_iter()
+                        .collect(),
+                    flags: vec![],
+                    content: "let x = a < b && c;\n".to_string(),
+                    is_diagram: false,
+                    range: 0..47,
+                },
+                CodeBlock {
+                    lang: Some("mermaid".to_string()),
+                    info: "mermaid".to_string(),
+                    attributes: HashMap::new(),
+                    flags: vec![],
+                    content: "A-->B\n".to_string(),
+                    is_diagram: true,
+                    range: 49..69,
+                },
+                CodeBlock {
+                    lang: None,
+                    info: String::new(),
+                    attributes: HashMap::new(),
+                    flags: vec![],
+                    content: "indented :smile:\n".to_string(),
+                    is_diagram: false,
+                    range: 75..92,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_code_html_into_lines_with_balanced_spans() {
+        let html = "<span class=\"a\">one\n<span class=\"b\">two</span>\nthree</span>\n";
+
+        assert_eq!(
+            html_lines(html),
+            vec![
+                "<span class=\"a\">one</span>",
+                "<span class=\"a\"><span class=\"b\">two</span></span>",
+                "<span class=\"a\">three</span>",
+            ]
+        );
+    }
+
+    #[test]
+    fn gathers_a_list_of_links_on_the_page() {
+        let input = indoc! {"
+        [foo](/bar)
+
+        [Example](https://www.example.com)
+        "};
+
+        let options = ParseOptions::default();
+
+        let Markdown {
+            as_html: _as_html,
+            headings: _headings,
+            links,
+            ..
+        } = parse(&input, Some(options));
+
+        assert_eq!(
+            links,
+            vec![
+                Link {
+                    title: "foo".to_string(),
+                    url: UrlType::Local("/bar".into()),
+                    kind: LinkSyntax::Inline,
+                    range: 0..11,
+                    section_anchor: None,
+                    original: "/bar".to_string(),
+                },
+                Link {
+                    title: "Example".to_string(),
+                    url: UrlType::Remote(Url::parse("https://www.example.com").unwrap()),
+                    kind: LinkSyntax::Inline,
+                    range: 13..47,
+                    section_anchor: None,
+                    original: "https://www.example.com".to_string(),
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn link_titles_only_hold_the_link_text() {
+        let input = indoc! {"
+        [foo](/bar \"A title\") and [baz](/qux)
+        "};
+
+        let Markdown { links, .. } = parse(input, None);
+
+        assert_eq!(links[0].title, "foo");
+        assert_eq!(links[1].title, "baz");
+    }
+
+    #[test]
+    fn gathers_reference_style_links() {
+        let input = indoc! {"
+        [reference][1], [collapsed][] and [shortcut]
+
+        [1]: /guide/install
+        [collapsed]: https://www.example.com/collapsed
+        [shortcut]: /guide/shortcut
+        "};
+
+        let Markdown {
+            as_html: _as_html,
+            headings: _headings,
+            links,
+            ..
+        } = parse(input, None);
+
+        assert_eq!(
+            links,
+            vec![
+                Link {
+                    title: "reference".to_string(),
+                    url: UrlType::Local("/guide/install".into()),
+                    kind: LinkSyntax::Reference,
+                    range: 0..14,
+                    section_anchor: None,
+                    original: "/guide/install".to_string(),
+                },
+                Link {
+                    title: "collapsed".to_string(),
+                    url: UrlType::Remote(Url::parse("https://www.example.com/collapsed").unwrap()),
+                    kind: LinkSyntax::Collapsed,
+                    range: 16..27,
+                    section_anchor: None,
+                    original: "https://www.example.com/collapsed".to_string(),
+                },
+                Link {
+                    title: "shortcut".to_string(),
+                    url: UrlType::Local("/guide/shortcut".into()),
+                    kind: LinkSyntax::Shortcut,
+                    range: 34..44,
+                    section_anchor: None,
+                    original: "/guide/shortcut".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn gathers_autolinks() {
+        let input = indoc! {"
+        Visit <https://www.example.com/status> or email <support@example.com>
+        "};
+
+        let mut options = ParseOptions::default();
+        options
+            .url_params
+            .insert("base".to_owned(), "123".to_owned());
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p>Visit <a href=\"https://www.example.com/status\">https://www.example.com/status</a> or email <a href=\"mailto:support@example.com\">support@example.com</a></p>
+            "}
+        );
+
+        assert_eq!(
+            links,
+            vec![
+                Link {
+                    title: "https://www.example.com/status".to_string(),
+                    url: UrlType::Remote(Url::parse("https://www.example.com/status").unwrap()),
+                    kind: LinkSyntax::Autolink,
+                    range: 6..38,
+                    section_anchor: None,
+                    original: "https://www.example.com/status".to_string(),
+                },
+                Link {
+                    title: "support@example.com".to_string(),
+                    url: UrlType::Email("support@example.com".to_string()),
+                    kind: LinkSyntax::Email,
+                    range: 48..69,
+                    section_anchor: None,
+                    original: "support@example.com".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn handles_mailto_links() {
+        let input = indoc! {"
+        [email us](mailto:support@example.com) or [ask](mailto:help@example.com?subject=Hi%20there)
+        "};
+
+        let mut options = ParseOptions {
+            url_root: "/docs".to_owned(),
+            ..ParseOptions::default()
+        };
+        options
+            .url_params
+            .insert("base".to_owned(), "123".to_owned());
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"mailto:support@example.com\">email us</a> or <a href=\"mailto:help@example.com?subject=Hi%20there\">ask</a></p>
+            "}
+        );
+
+        assert_eq!(
+            links.iter().map(|l| l.url.clone()).collect::<Vec<_>>(),
+            vec![
+                UrlType::Email("support@example.com".to_string()),
+                UrlType::Email("help@example.com".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn classifies_fragment_only_links_as_anchors() {
+        let input = indoc! {"
+        [see above](#installation)
+        "};
+
+        let mut options = ParseOptions {
+            url_root: "/docs".to_owned(),
+            ..ParseOptions::default()
+        };
+        options
+            .url_params
+            .insert("base".to_owned(), "123".to_owned());
+
+        let Markdown {
+            as_html,
+            headings: _headings,
+            links,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"#installation\">see above</a></p>
+            "}
+        );
+
+        assert_eq!(
+            links,
+            vec![Link {
+                title: "see above".to_string(),
+                url: UrlType::Anchor("installation".to_string()),
+                kind: LinkSyntax::Inline,
+                range: 0..26,
+                section_anchor: None,
+                original: "#installation".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn splits_the_query_and_fragment_from_local_links() {
+        let input = indoc! {"
+        [config](/guide/setup#env-vars)
+
+        [report](./report?tab=summary#totals)
+        "};
+
+        let Markdown {
+            as_html: _as_html,
+            headings: _headings,
+            links,
+            ..
+        } = parse(input, None);
+
+        assert_eq!(
+            links.iter().map(|l| l.url.clone()).collect::<Vec<_>>(),
+            vec![
+                UrlType::Local(LocalUrl {
+                    path: "/guide/setup".into(),
+                    query: None,
+                    fragment: Some("env-vars".to_string()),
+                }),
+                UrlType::Local(LocalUrl {
+                    path: "report".into(),
+                    query: Some("tab=summary".to_string()),
+                    fragment: Some("totals".to_string()),
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn normalizes_dot_segments_in_local_paths() {
+        let cases = [
+            ("./../shared/notes.md", "../shared/notes.md"),
+            ("/guide/./setup/../install", "/guide/install"),
+            ("/guide/setup/..", "/guide/"),
+            ("a/b/../../..", "../"),
+            ("./", "."),
+            ("/../secrets", "/../secrets"),
+            ("/guide/install", "/guide/install"),
+        ];
+
+        for (path, expected) in cases {
+            assert_eq!(normalize_dot_segments(path), expected, "path {:?}", path);
+        }
+    }
+
+    #[test]
+    fn warns_about_links_that_escape_the_site_root() {
+        let input = indoc! {"
+        [notes](./../shared/notes.md) [secrets](/guide/../../secrets)
+        "};
+
+        let Markdown {
+            links, warnings, ..
+        } = parse(input, None);
+
+        assert_eq!(
+            links[0].url,
+            UrlType::Local(LocalUrl::from("../shared/notes.md"))
+        );
+        assert_eq!(
+            warnings,
+            vec![Warning {
+                kind: WarningKind::EscapesRoot("/../secrets".to_string()),
+                range: 30..61,
+            }]
+        );
+    }
+
+    #[test]
+    fn resolves_local_urls_against_the_base_url() {
+        let input = indoc! {"
+        [guide](/guide) [page](page?x=1#top) [top](#top) [other](https://example.com/a) <hi@example.com>
+        ![cat](/cat.jpg)
+        "};
+
+        let options = ParseOptions {
+            url_root: "/docs".to_owned(),
+            base_url: Some(Url::parse("https://docs.example.com/v1/").unwrap()),
+            valid_paths: Some(std::iter::once(PathBuf::from("/docs/guide")).collect()),
+            external_links_in_new_tab: true,
+            ..ParseOptions::default()
+        };
+
+        let Markdown {
+            as_html,
+            links,
+            broken_links,
+            ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"https://docs.example.com/docs/guide\">guide</a> <a href=\"https://docs.example.com/v1/page?x=1#top\">page</a> <a href=\"#top\">top</a> <a href=\"https://example.com/a\" target=\"_blank\" rel=\"noopener noreferrer\">other</a> <a href=\"mailto:hi@example.com\">hi@example.com</a>
+                <img src=\"https://docs.example.com/docs/cat.jpg\" alt=\"cat\"></p>
+            "}
+        );
+        assert_eq!(
+            links[0].url,
+            UrlType::Remote(Url::parse("https://docs.example.com/docs/guide").unwrap())
+        );
+        assert_eq!(broken_links.len(), 1);
+        assert_eq!(broken_links[0].title, "page");
+    }
+
+    #[test]
+    fn reports_local_links_missing_from_the_valid_paths() {
+        let input = indoc! {"
+        [exists](/guide/install#requirements) [missing](/guide/missing)
+        [renamed](/old/page) [anchor](#setup) [remote](https://www.example.com/nope)
+        "};
+
+        let mut options = ParseOptions {
+            url_root: "/docs".to_owned(),
+            ..ParseOptions::default()
+        };
+        options
+            .link_rewrite_rules
+            .insert("/old/page".to_owned(), "/docs/new/page".into());
+
+        let mut valid_paths = HashSet::new();
+        valid_paths.insert(PathBuf::from("/docs/guide/install"));
+        valid_paths.insert(PathBuf::from("/docs/new/page"));
+        options.valid_paths = Some(valid_paths);
+
+        let Markdown { broken_links, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            broken_links,
+            vec![Link {
+                title: "missing".to_string(),
+                url: UrlType::Local("/docs/guide/missing".into()),
+                kind: LinkSyntax::Inline,
+                range: 38..63,
+                section_anchor: None,
+                original: "/guide/missing".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn does_not_check_links_without_valid_paths() {
+        let Markdown { broken_links, .. } = parse("[missing](/guide/missing)", None);
+
+        assert!(broken_links.is_empty());
+    }
+
+    #[test]
+    fn leaves_links_with_other_schemes_alone() {
+        let input = indoc! {"
+        [call](tel:+1-555-0100) [text](sms:+1-555-0100) [chat](slack://channel?team=T1&id=C1)
+        "};
+
+        let mut options = ParseOptions {
+            url_root: "/docs".to_owned(),
+            ..ParseOptions::default()
+        };
+        options
+            .url_params
+            .insert("base".to_owned(), "123".to_owned());
+
+        let Markdown { as_html, links, .. } = parse(input, Some(options));
+
+        assert!(as_html.contains("<a href=\"tel:+1-555-0100\">call</a>"));
+        assert!(as_html.contains("<a href=\"sms:+1-555-0100\">text</a>"));
+
+        assert_eq!(
+            links.iter().map(|l| l.url.clone()).collect::<Vec<_>>(),
+            vec![
+                UrlType::Remote(Url::parse("tel:+1-555-0100").unwrap()),
+                UrlType::Remote(Url::parse("sms:+1-555-0100").unwrap()),
+                UrlType::Remote(Url::parse("slack://channel?team=T1&id=C1").unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn optionally_autolinks_bare_urls() {
+        let input = indoc! {"
+        See https://example.com/status. Or www.example.com, (https://example.com/a_(b)) and `https://example.com/code`
+
+        ```
+        https://example.com/block
+        ```
+
+        [https://example.com/linked](/local)
+        "};
+
+        let options = ParseOptions {
+            autolink_bare_urls: true,
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, links, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p>See <a href=\"https://example.com/status\">https://example.com/status</a>. Or <a href=\"http://www.example.com\">www.example.com</a>, (<a href=\"https://example.com/a_(b)\">https://example.com/a_(b)</a>) and <code>https://example.com/code</code></p>
+                <pre><code>https://example.com/block
+                </code></pre>
+                <p><a href=\"/local\">https://example.com/linked</a></p>
+            "}
+        );
+
+        assert_eq!(
+            links[0],
+            Link {
+                title: "https://example.com/status".to_string(),
+                url: UrlType::Remote(Url::parse("https://example.com/status").unwrap()),
+                kind: LinkSyntax::Autolink,
+                range: 4..30,
+                section_anchor: None,
+                original: "https://example.com/status".to_string(),
+            }
+        );
+        assert_eq!(links.len(), 4);
+    }
+
+    #[test]
+    fn does_not_autolink_bare_urls_in_html_links_or_images() {
+        let input = indoc! {"
+        <a href=\"/x\">https://example.com</a> and https://example.com/after
+
+        ![see https://example.com/x](/i.png)
+        "};
+
+        let options = ParseOptions {
+            autolink_bare_urls: true,
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, links, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"/x\">https://example.com</a> and <a href=\"https://example.com/after\">https://example.com/after</a></p>
+                <p><img src=\"/i.png\" alt=\"see https://example.com/x\"></p>
+            "}
+        );
+        assert_eq!(
+            links.iter().map(|l| l.title.as_str()).collect::<Vec<_>>(),
+            vec!["https://example.com/after"]
+        );
+    }
+
+    #[test]
+    fn does_not_autolink_bare_urls_by_default() {
+        let Markdown { as_html, .. } = parse("See https://example.com/status", None);
+
+        assert_eq!(as_html, "<p>See https://example.com/status</p>\n");
+    }
+
+    #[test]
+    fn finds_the_line_and_column_of_a_link() {
+        let input = indoc! {"
+        # Heading
+
+        Some text and ünicode [an link](/foo)
+        "};
+
+        let Markdown { links, .. } = parse(input, None);
+
+        assert_eq!(&input[links[0].range.clone()], "[an link](/foo)");
+        assert_eq!(line_and_column(input, links[0].range.start), (3, 23));
+    }
+
+    #[test]
+    fn keeps_the_original_url_of_links_and_images() {
+        let input = indoc! {"
+        [guide](/guide/README.md) ![cat](/cat.jpg) [old][1]
+
+        [1]: /old/page
+        "};
+
+        let mut options = ParseOptions {
+            url_root: "/docs".to_owned(),
+            ..ParseOptions::default()
+        };
+        options
+            .link_rewrite_rules
+            .insert("/old/page".to_owned(), "/new/page".into());
+
+        let Markdown { links, images, .. } = parse(input, Some(options));
+
+        let links: Vec<_> = links
+            .iter()
+            .map(|link| (link.original.as_str(), link.url.clone()))
+            .collect();
+
+        assert_eq!(
+            links,
+            vec![
+                ("/guide/README.md", UrlType::Local("/docs/guide/".into())),
+                ("/old/page", UrlType::Local("/new/page".into())),
+            ]
+        );
+        assert_eq!(images[0].original, "/cat.jpg");
+        assert_eq!(images[0].src, UrlType::Local("/docs/cat.jpg".into()));
+    }
+
+    #[test]
+    fn deduplicates_links_by_their_resolved_url() {
+        let input = indoc! {"
+        [Install](./page) [again](page) [Other](/other) [elsewhere](https://example.com)
+        [Other again](/other)
+
+        [ref][1]
+
+        [1]: https://example.com
+        "};
+
+        let markdown = parse(input, None);
+
+        assert_eq!(
+            markdown.unique_links(),
+            vec![
+                UniqueLink {
+                    url: UrlType::Local("./page".into()),
+                    title: "Install".to_string(),
+                    count: 2,
+                },
+                UniqueLink {
+                    url: UrlType::Local("/other".into()),
+                    title: "Other".to_string(),
+                    count: 2,
+                },
+                UniqueLink {
+                    url: UrlType::Remote(Url::parse("https://example.com").unwrap()),
+                    title: "elsewhere".to_string(),
+                    count: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn renders_image_sizes() {
+        let input = indoc! {"
+        ![video still](/still.png =640x360) ![logo](logo.svg =120x \"Our logo\")
+
+        ![broken](/cat.png =640) `![code](x.png =1x2)`
+        "};
+
+        let options = ParseOptions {
+            url_root: "/docs".to_owned(),
+            ..ParseOptions::default()
+        };
+
+        let Markdown {
+            as_html, images, ..
+        } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><img src=\"/docs/still.png\" alt=\"video still\" width=\"640\" height=\"360\"> <img src=\"logo.svg\" alt=\"logo\" title=\"Our logo\" width=\"120\"></p>
+                <p>![broken](/cat.png =640) <code>![code](x.png =1x2)</code></p>
+            "}
+        );
+        assert_eq!(images[0].src, UrlType::Local("/docs/still.png".into()));
+        assert_eq!(images[0].original, "/still.png");
+        assert_eq!((images[0].width, images[0].height), (Some(640), Some(360)));
+        assert_eq!((images[1].width, images[1].height), (Some(120), None));
+    }
+
+    #[test]
+    fn wraps_standalone_images_with_a_title_in_figures() {
+        let input = indoc! {"
+        ![diagram](arch.png \"System <architecture>\")
+
+        ![sized](arch.png =640x \"Sized\")
+
+        An inline ![diagram](arch.png \"Inline\") image.
+
+        ![no title](arch.png)
+        "};
+
+        let options = ParseOptions {
+            image_figures: true,
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <figure><img src=\"arch.png\" alt=\"diagram\" title=\"System <architecture>\"><figcaption>System &lt;architecture&gt;</figcaption></figure>
+                <figure><img src=\"arch.png\" alt=\"sized\" title=\"Sized\" width=\"640\"><figcaption>Sized</figcaption></figure>
+                <p>An inline <img src=\"arch.png\" alt=\"diagram\" title=\"Inline\"> image.</p>
+                <p><img src=\"arch.png\" alt=\"no title\"></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn warns_about_images_without_alt_text() {
+        let input = indoc! {"
+        ![](diagram.png) ![described](cat.png)
+        "};
+
+        let Markdown {
+            as_html, warnings, ..
+        } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><img src=\"diagram.png\" alt=\"\"> <img src=\"cat.png\" alt=\"described\"></p>
+            "}
+        );
+        assert_eq!(
+            warnings,
+            vec![Warning {
+                kind: WarningKind::MissingAltText("diagram.png".to_string()),
+                range: 0..16,
+            }]
+        );
+
+        let options = ParseOptions {
+            warn_missing_alt_text: false,
+            ..ParseOptions::default()
+        };
+
+        assert_eq!(parse(input, Some(options)).warnings, vec![]);
+    }
+
+    #[test]
+    fn adds_responsive_variants_from_the_image_resolver() {
+        let input = indoc! {"
+        ![cat](/cat.jpg) ![diagram](/diagram.svg)
+        "};
+
+        let resolver = |src: &str| {
+            let stem = src.strip_suffix(".jpg")?;
+
+            Some(ImageVariants {
+                srcset: vec![
+                    (format!("{}-480w.jpg", stem), "480w".to_string()),
+                    (format!("{}-960w.jpg", stem), "960w".to_string()),
+                ],
+                sizes: Some("(max-width: 600px) 480px, 960px".to_string()),
+            })
+        };
+
+        let options = ParseOptions {
+            url_root: "/docs".to_owned(),
+            hooks: Hooks {
+                image_resolver: Some(Arc::new(resolver)),
+                ..Hooks::default()
+            },
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><img src=\"/docs/cat.jpg\" alt=\"cat\" srcset=\"/docs/cat-480w.jpg 480w, /docs/cat-960w.jpg 960w\" sizes=\"(max-width: 600px) 480px, 960px\"> <img src=\"/docs/diagram.svg\" alt=\"diagram\"></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn finds_the_first_image_of_the_document() {
+        let input = indoc! {"
+        ```markdown
+        ![not an image](/code.png)
+        ```
+
+        Some `![code](/inline.png)` and then ![Our logo](/logo.png).
+
+        ![second](/second.png)
+        "};
+
+        let mut options = ParseOptions::default();
+        options
+            .link_rewrite_rules
+            .insert("/logo.png".to_owned(), "/assets/logo.png".into());
+
+        let markdown = parse(input, Some(options));
+        let image = markdown.first_image().unwrap();
+
+        assert_eq!(image.src, UrlType::Local("/assets/logo.png".into()));
+        assert_eq!(image.alt, "Our logo");
+
+        assert_eq!(parse("No images here", None).first_image(), None);
+    }
+
+    #[test]
+    fn optionally_allows_data_url_images() {
+        let input = indoc! {"
+        ![dot](data:image/png;base64,iVBORw0KGgo=) ![page](data:text/html;base64,PHNjcmlwdD4=)
+        [link](data:image/png;base64,iVBORw0KGgo=)
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><img alt=\"dot\"> <img alt=\"page\">
+                <a>link</a></p>
+            "}
+        );
+
+        let options = ParseOptions {
+            allow_data_image_urls: true,
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><img src=\"data:image/png;base64,iVBORw0KGgo=\" alt=\"dot\"> <img alt=\"page\">
+                <a>link</a></p>
+            "}
+        );
+    }
+
+    #[test]
+    fn records_the_link_wrapping_an_image() {
+        let input = indoc! {"
+        [![thumb](/img/thumb.png)](/img/full.png)
+        "};
+
+        let options = ParseOptions {
+            url_root: "/docs".to_owned(),
+            ..ParseOptions::default()
+        };
+
+        let Markdown { links, images, .. } = parse(input, Some(options));
+
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].url, UrlType::Local("/docs/img/full.png".into()));
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].src, UrlType::Local("/docs/img/thumb.png".into()));
+        assert_eq!(
+            images[0].link,
+            Some(UrlType::Local("/docs/img/full.png".into()))
+        );
+    }
+
+    #[test]
+    fn wraps_zoomable_images_in_a_link() {
+        let input = indoc! {"
+        ![big](/big.png =1200x) ![small](/small.png =300x) ![unsized](/unsized.png)
+        [![linked](/linked.png =1200x)](/elsewhere)
+        "};
+
+        let options = ParseOptions {
+            url_root: "/docs".to_owned(),
+            zoomable_images: ZoomableImages::WiderThan(800),
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p><a href=\"/docs/big.png\" class=\"zoom\"><img src=\"/docs/big.png\" alt=\"big\" width=\"1200\" data-zoomable=\"\"></a> <img src=\"/docs/small.png\" alt=\"small\" width=\"300\"> <img src=\"/docs/unsized.png\" alt=\"unsized\">
+                <a href=\"/docs/elsewhere\"><img src=\"/docs/linked.png\" alt=\"linked\" width=\"1200\"></a></p>
+            "}
+        );
+
+        let options = ParseOptions {
+            zoomable_images: ZoomableImages::Always,
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse("![cat](https://example.com/cat.png)", Some(options));
+
+        assert_eq!(
+            as_html,
+            "<p><a href=\"https://example.com/cat.png\" class=\"zoom\"><img src=\"https://example.com/cat.png\" alt=\"cat\" data-zoomable=\"\"></a></p>\n"
+        );
+    }
+
+    #[test]
+    fn keeps_numeric_dimensions_on_raw_html_images() {
+        let input = indoc! {"
+        Inline <img src=\"/img/x.png\" width=\"300\" height=\"200\" alt=\"x\"> image.
+
+        <img src=\"/img/y.png\" width=\" 120 \" height=\"calc(100% - 1px)\">
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <p>Inline <img src=\"/img/x.png\" width=\"300\" height=\"200\" alt=\"x\"> image.</p>
+                <img src=\"/img/y.png\" width=\"120\">
+            "}
+        );
+    }
+
+    #[test]
+    fn gathers_a_list_of_images_on_the_page() {
+        let input = indoc! {"
+        ![A *cat*](/assets/cat.jpg \"The cat\")
+
+        [![thumb][dog]](/full.png)
+
+        [dog]: https://example.com/dog.jpg
+        "};
+
+        let mut options = ParseOptions::default();
+        options
+            .link_rewrite_rules
+            .insert("/assets/".to_owned(), "https://cdn.example.com/".into());
+
+        let Markdown { images, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            images,
+            vec![
+                Image {
+                    src: UrlType::Remote(Url::parse("https://cdn.example.com/cat.jpg").unwrap()),
+                    alt: "A cat".to_string(),
+                    title: Some("The cat".to_string()),
+                    range: 0..37,
+                    original: "/assets/cat.jpg".to_string(),
+                    width: None,
+                    height: None,
+                    link: None,
+                },
+                Image {
+                    src: UrlType::Remote(Url::parse("https://example.com/dog.jpg").unwrap()),
+                    alt: "thumb".to_string(),
+                    title: None,
+                    range: 40..53,
+                    original: "https://example.com/dog.jpg".to_string(),
+                    width: None,
+                    height: None,
+                    link: Some(UrlType::Local("/full.png".into())),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn does_not_gather_footnote_references_as_links() {
+        let input = indoc! {"
+        Text[^1] and [a link](/foo).
+
+        [^1]: https://example.com/footnote
         "};
 
-        let Markdown {
-            as_html,
-            headings,
-            links: _,
-        } = parse(&input, None);
+        let options = ParseOptions {
+            footnotes: true,
+            ..ParseOptions::default()
+        };
+
+        let Markdown { links, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            links.iter().map(|l| l.title.as_str()).collect::<Vec<_>>(),
+            vec!["a link"]
+        );
+    }
+
+    #[test]
+    fn footnotes_survive_sanitizing() {
+        let input = indoc! {"
+        # Notes
+
+        Text[^note] and more[^2], again[^note].
+
+        [^note]: The note.
+
+        [^2]: Another note.
+
+        <div id=\"evil\"><sup id=\"notes\" class=\"evil\">x</sup></div>
+
+        <ul><li id=\"fn-evil\"><sup id=\"fnref-evil\">y</sup></li></ul>
+        "};
+
+        let options = ParseOptions {
+            footnotes: true,
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, .. } = parse(input, Some(options));
 
         assert_eq!(
             as_html,
             indoc! {"
-                <h1 id=\"my-heading\">My heading</h1>
-                <p>Some content</p>
-                <h2 id=\"some-other-heading\">Some other heading</h2>
+                <h1 id=\"notes\">Notes</h1>
+                <p>Text<sup class=\"footnote-reference\" id=\"fnref-note\"><a href=\"#fn-note\">1</a></sup> and more<sup class=\"footnote-reference\" id=\"fnref-2\"><a href=\"#fn-2\">2</a></sup>, again<sup class=\"footnote-reference\"><a href=\"#fn-note\">1</a></sup>.</p>
+                <div><sup class=\"\">x</sup></div>
+                <ul><li><sup>y</sup></li></ul>
+                <section class=\"footnotes\">
+                <ol>
+                <li id=\"fn-note\">
+                <p>The note. <a href=\"#fnref-note\" class=\"footnote-backref\">↩</a></p>
+                </li>
+                <li id=\"fn-2\">
+                <p>Another note. <a href=\"#fnref-2\" class=\"footnote-backref\">↩</a></p>
+                </li>
+                </ol>
+                </section>
             "}
         );
+    }
+
+    #[test]
+    fn renders_footnotes_at_the_end() {
+        let input = indoc! {"
+        # Intro
+
+        First[^b] then[^a] and a [link](#fn-a)[^missing].
+
+        ## Outro
+
+        [^a]: Defined first.
+
+        [^b]: > Quoted.
+
+        [^unused]: Never referenced.
+        "};
+
+        let options = ParseOptions {
+            footnotes: true,
+            ..ParseOptions::default()
+        };
+
+        let Markdown {
+            as_html, warnings, ..
+        } = parse(input, Some(options));
 
         assert_eq!(
-            headings,
-            vec![
-                Heading {
-                    title: "My heading".to_string(),
-                    anchor: "my-heading".to_string(),
-                    level: 1,
-                },
-                Heading {
-                    title: "Some other heading".to_string(),
-                    anchor: "some-other-heading".to_string(),
-                    level: 2,
-                }
-            ]
+            as_html,
+            indoc! {"
+                <h1 id=\"intro\">Intro</h1>
+                <p>First<sup class=\"footnote-reference\" id=\"fnref-b\"><a href=\"#fn-b\">1</a></sup> then<sup class=\"footnote-reference\" id=\"fnref-a\"><a href=\"#fn-a\">2</a></sup> and a <a href=\"#fn-a\">link</a>[^missing].</p>
+                <h2 id=\"outro\">Outro</h2>
+                <section class=\"footnotes\">
+                <ol>
+                <li id=\"fn-b\">
+                <blockquote>
+                <p>Quoted.</p>
+                </blockquote>
+                <a href=\"#fnref-b\" class=\"footnote-backref\">↩</a></li>
+                <li id=\"fn-a\">
+                <p>Defined first. <a href=\"#fnref-a\" class=\"footnote-backref\">↩</a></p>
+                </li>
+                </ol>
+                </section>
+            "}
+        );
+        assert_eq!(
+            warnings,
+            vec![Warning {
+                kind: WarningKind::UndefinedFootnote("missing".to_string()),
+                range: 47..57,
+            }]
         );
     }
 
     #[test]
-    fn optionally_rewrites_link_root_path() {
+    fn numbers_footnotes_referenced_from_other_footnotes() {
         let input = indoc! {"
-        [an link](/foo/bar)
+        a[^a]
+
+        [^a]: See[^b].
+
+        [^b]: B[^missing].
         "};
 
+        let options = ParseOptions {
+            footnotes: true,
+            ..ParseOptions::default()
+        };
+
         let Markdown {
-            as_html,
-            headings: _headings,
-            links: _,
-        } = parse(&input, None);
+            as_html, warnings, ..
+        } = parse(input, Some(options));
 
         assert_eq!(
             as_html,
             indoc! {"
-                <p><a href=\"/foo/bar\">an link</a></p>
+                <p>a<sup class=\"footnote-reference\" id=\"fnref-a\"><a href=\"#fn-a\">1</a></sup></p>
+                <section class=\"footnotes\">
+                <ol>
+                <li id=\"fn-a\">
+                <p>See<sup class=\"footnote-reference\" id=\"fnref-b\"><a href=\"#fn-b\">2</a></sup>. <a href=\"#fnref-a\" class=\"footnote-backref\">↩</a></p>
+                </li>
+                <li id=\"fn-b\">
+                <p>B[^missing]. <a href=\"#fnref-b\" class=\"footnote-backref\">↩</a></p>
+                </li>
+                </ol>
+                </section>
             "}
         );
+        assert_eq!(
+            warnings,
+            vec![Warning {
+                kind: WarningKind::UndefinedFootnote("missing".to_string()),
+                range: 30..40,
+            }]
+        );
+    }
 
-        let mut options = ParseOptions::default();
-        options.url_root = "/other/root".to_owned();
+    #[test]
+    fn can_use_smart_punctuation() {
+        let input = indoc! {"
+        # Don't 'panic' -- ok... `a--b`
 
-        let Markdown {
-            as_html,
-            headings: _headings,
-            links: _,
-        } = parse(&input, Some(options));
+        It's 'quoted' --- really...
+
+        ```
+        \"code\" -- stays...
+        ```
+        "};
+
+        let options = ParseOptions {
+            smart_punctuation: true,
+            ..ParseOptions::default()
+        };
+
+        let smart = parse(input, Some(options));
+        let plain = parse(input, None);
 
         assert_eq!(
-            as_html,
+            smart.as_html,
             indoc! {"
-                <p><a href=\"/other/root/foo/bar\">an link</a></p>
+                <h1 id=\"don't-'panic'----ok...-a--b\">Don’t ‘panic’ – ok… <code>a--b</code></h1>
+                <p>It’s ‘quoted’ — really…</p>
+                <pre><code>\"code\" -- stays...
+                </code></pre>
             "}
         );
+        assert_eq!(
+            smart.headings,
+            vec![Heading {
+                title: "Don’t ‘panic’ – ok… a--b".to_string(),
+                anchor: plain.headings[0].anchor.clone(),
+                level: 1,
+            }]
+        );
     }
 
     #[test]
-    fn does_not_rewrite_non_absolute_urls() {
+    fn can_use_definition_lists() {
         let input = indoc! {"
-        [an link](https://www.google.com)
+        Apple
+        : A *fruit*.
+        : A company.
+
+        Orange
+        Tangerine
+
+        :   A citrus fruit.
+
+            Also a colour.
+
+        Not a term.
         "};
 
-        let mut options = ParseOptions::default();
-        options.url_root = "/other/root".to_owned();
+        let options = ParseOptions {
+            definition_lists: true,
+            ..ParseOptions::default()
+        };
 
-        let Markdown {
-            as_html,
-            headings: _headings,
-            links: _,
-        } = parse(&input, Some(options));
         assert_eq!(
-            as_html,
+            parse(input, Some(options)).as_html,
             indoc! {"
-                <p><a href=\"https://www.google.com\">an link</a></p>
+                <dl>
+                <dt>Apple</dt>
+                <dd>A <em>fruit</em>.</dd>
+                <dd>A company.</dd>
+                <dt>Orange</dt>
+                <dt>Tangerine</dt>
+                <dd>
+                <p>A citrus fruit.</p>
+                <p>Also a colour.</p>
+                </dd>
+                </dl>
+                <p>Not a term.</p>
             "}
         );
+    }
 
+    #[test]
+    fn definition_lists_leave_other_markdown_alone() {
         let input = indoc! {"
-        [an link](relative/link)
+        # Colons
+
+        - A list
+        : not a definition
+
+        > Quoted
+        > : not a definition
+
+        ```
+        Code
+        : not a definition
+        ```
         "};
 
-        let mut options = ParseOptions::default();
-        options.url_root = "/other/root".to_owned();
+        let options = ParseOptions {
+            definition_lists: true,
+            ..ParseOptions::default()
+        };
 
-        let Markdown {
-            as_html,
-            headings: _headings,
-            links: _,
-        } = parse(&input, Some(options));
+        assert_eq!(
+            parse(input, Some(options)).as_html,
+            parse(input, None).as_html
+        );
+    }
+
+    #[test]
+    fn can_use_admonitions() {
+        let input = indoc! {"
+        :::warning Mind <the> gap
+        Some *text*.
+
+        - A list
+        :::
+
+        :::tip
+        # Inside
+
+        ::: custom
+        Nested.
+        :::
+        :::
+
+        ```
+        :::note
+        ```
+        "};
+
+        let options = ParseOptions {
+            admonitions: true,
+            ..ParseOptions::default()
+        };
+
+        let markdown = parse(input, Some(options));
 
         assert_eq!(
-            as_html,
+            markdown.as_html,
             indoc! {"
-                <p><a href=\"relative/link\">an link</a></p>
+                <div class=\"admonition warning\">
+                <p class=\"admonition-title\">Mind &lt;the&gt; gap</p>
+                <p>Some <em>text</em>.</p>
+                <ul>
+                <li>A list</li>
+                </ul>
+                </div>
+                <div class=\"admonition tip\">
+                <p class=\"admonition-title\">Tip</p>
+                <h1 id=\"inside\">Inside</h1>
+                <div class=\"admonition\">
+                <p class=\"admonition-title\">Custom</p>
+                <p>Nested.</p>
+                </div>
+                </div>
+                <pre><code>:::note
+                </code></pre>
             "}
         );
+        assert_eq!(markdown.headings[0].title, "Inside");
     }
 
     #[test]
-    fn rewrites_any_image_that_has_an_explicit_rewrite_mapping() {
+    fn leaves_unmatched_admonition_fences_alone() {
         let input = indoc! {"
-        ![an image](/assets/cat.jpg)
+        :::note
+        Never closed.
         "};
 
-        let mut options = ParseOptions::default();
+        let options = ParseOptions {
+            admonitions: true,
+            ..ParseOptions::default()
+        };
 
-        options.link_rewrite_rules.insert(
-            "/assets/cat.jpg".to_owned(),
-            "https://example.com/cat.jpg".to_owned(),
+        assert_eq!(
+            parse(input, Some(options)).as_html,
+            parse(input, None).as_html
         );
+    }
 
-        let Markdown {
+    #[test]
+    fn can_use_collapsible_sections() {
+        let input = indoc! {"
+        :::details Click <to> expand
+        # Hidden
+
+        :::note
+        Inside.
+        :::
+        :::
+
+        :::details open
+        Shown.
+        :::
+        "};
+
+        let options = ParseOptions {
+            admonitions: true,
+            collapsible_sections: true,
+            ..ParseOptions::default()
+        };
+
+        let markdown = parse(input, Some(options.clone()));
+
+        assert_eq!(
+            markdown.as_html,
+            indoc! {"
+                <details>
+                <summary>Click &lt;to&gt; expand</summary>
+                <h1 id=\"hidden\">Hidden</h1>
+                <div class=\"admonition note\">
+                <p class=\"admonition-title\">Note</p>
+                <p>Inside.</p>
+                </div>
+                </details>
+                <details open=\"\">
+                <summary>Details</summary>
+                <p>Shown.</p>
+                </details>
+            "}
+        );
+        assert_eq!(markdown.headings[0].anchor, "hidden");
+
+        let markdown = parse(
+            input,
+            Some(ParseOptions {
+                exclude_collapsible_headings: true,
+                ..options
+            }),
+        );
+
+        assert!(markdown.headings.is_empty());
+        assert_eq!(markdown.anchors(), vec!["hidden"]);
+    }
+
+    #[test]
+    fn collapsible_sections_dont_need_admonitions() {
+        let input = indoc! {"
+        :::details
+        :::note
+        Text.
+        :::
+        :::
+        "};
+
+        let options = ParseOptions {
+            collapsible_sections: true,
+            ..ParseOptions::default()
+        };
+
+        assert_eq!(
+            parse(input, Some(options)).as_html,
+            indoc! {"
+                <details>
+                <summary>Details</summary>
+                <p>:::note
+                Text.
+                :::</p>
+                </details>
+            "}
+        );
+    }
+
+    #[test]
+    fn can_use_abbreviations() {
+        let input = indoc! {"
+        HTML and HTML5, but not HTMLs or `HTML`, see https://example.com/HTML.
+
+        *[HTML]: Hypertext
+        *[HTML]: HyperText Markup \"Language\"
+        *[HTML5]: The fifth HTML
+        *[CSS]: Cascading Style Sheets
+        *[CSS]:
+
+        Some CSS.
+
+        ```
+        HTML
+        *[JS]: JavaScript
+        ```
+        "};
+
+        let options = ParseOptions {
+            abbreviations: true,
+            ..ParseOptions::default()
+        };
+
+        assert_eq!(
+            parse(input, Some(options)).as_html,
+            indoc! {"
+                <p><abbr title=\"HyperText Markup &quot;Language&quot;\">HTML</abbr> and <abbr title=\"The fifth HTML\">HTML5</abbr>, but not HTMLs or <code>HTML</code>, see https://example.com/HTML.</p>
+                <p>Some CSS.</p>
+                <pre><code>HTML
+                *[JS]: JavaScript
+                </code></pre>
+            "}
+        );
+    }
+
+    #[test]
+    fn treats_protocol_relative_urls_as_remote() {
+        let input = indoc! {"
+        [cdn asset](//cdn.example.com/lib.js)
+        "};
+
+        let mut options = ParseOptions {
+            url_root: "/docs".to_owned(),
+            ..ParseOptions::default()
+        };
+        options
+            .url_params
+            .insert("base".to_owned(), "123".to_owned());
+
+        let Markdown { as_html, links, .. } = parse(input, Some(options));
+
+        assert_eq!(
             as_html,
-            headings: _headings,
-            links: _,
-        } = parse(&input, Some(options));
+            "<p><a href=\"//cdn.example.com/lib.js\">cdn asset</a></p>\n"
+        );
+        assert_eq!(
+            links[0].url,
+            UrlType::Remote(Url::parse("https://cdn.example.com/lib.js").unwrap())
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_url_when_link_text_is_empty() {
+        let input = indoc! {"
+        [](/config.md) [![](/cat.jpg)](/full.jpg)
+        "};
+
+        let options = ParseOptions {
+            url_root: "/docs".to_owned(),
+            ..ParseOptions::default()
+        };
+
+        let Markdown { as_html, links, .. } = parse(input, Some(options));
 
         assert_eq!(
             as_html,
             indoc! {"
-                <p><img src=\"https://example.com/cat.jpg\" alt=\"an image\"></p>
+                <p><a href=\"/docs/config.md\">/docs/config.md</a> <a href=\"/docs/full.jpg\"><img src=\"/docs/cat.jpg\" alt=\"\"></a></p>
             "}
         );
+        assert_eq!(links[0].title, "/docs/config.md");
+        assert_eq!(links[1].title, "");
+    }
+
+    #[test]
+    fn warns_about_links_to_missing_anchors() {
+        let input = indoc! {"
+        # Configuration
+
+        [ok](#configuration) [broken](#config) [self](/guide#installation)
+        [other page](/other#installation)
+        "};
+
+        let options = ParseOptions {
+            url_root: "/docs".to_owned(),
+            current_path: Some("/docs/guide".into()),
+            ..ParseOptions::default()
+        };
+
+        let Markdown { warnings, .. } = parse(input, Some(options));
+
+        assert_eq!(
+            warnings,
+            vec![
+                Warning {
+                    kind: WarningKind::BrokenAnchor("config".to_string()),
+                    range: 38..55,
+                },
+                Warning {
+                    kind: WarningKind::BrokenAnchor("installation".to_string()),
+                    range: 56..83,
+                },
+            ]
+        );
+        assert_eq!(
+            warnings[0].to_string(),
+            "link to missing anchor \"#config\""
+        );
+    }
+
+    #[test]
+    fn records_the_section_each_link_appears_under() {
+        let input = indoc! {"
+        [intro](/intro)
+
+        # Authentication
+
+        [login](/api/auth)
+
+        ## Tokens
+
+        [refresh](/api/refresh)
+        "};
+
+        let Markdown { links, .. } = parse(input, None);
+
+        let sections: Vec<_> = links
+            .iter()
+            .map(|link| link.section_anchor.as_deref())
+            .collect();
+
+        assert_eq!(sections, vec![None, Some("authentication"), Some("tokens")]);
     }
 
     #[test]
-    fn rewrites_any_link_that_has_an_explicit_rewrite_mapping() {
+    fn gathers_the_internal_text_of_a_link() {
         let input = indoc! {"
-        [an document](/assets/plans.pdf)
+        [**BOLD**](/bar)
+        [![AltText](/src/foo)](/bar)
+        ## [AnHeader](/bar)
         "};
 
-        let mut options = ParseOptions::default();
-
-        options.link_rewrite_rules.insert(
-            "/assets/plans.pdf".to_owned(),
-            "https://example.com/plans.pdf".to_owned(),
-        );
+        let options = ParseOptions::default();
 
         let Markdown {
-            as_html,
+            as_html: _as_html,
             headings: _headings,
-            links: _,
+            links,
+            ..
         } = parse(&input, Some(options));
 
         assert_eq!(
-            as_html,
-            indoc! {"
-                <p><a href=\"https://example.com/plans.pdf\">an document</a></p>
-            "}
+            links,
+            vec![
+                Link {
+                    title: "BOLD".to_string(),
+                    url: UrlType::Local("/bar".into()),
+                    kind: LinkSyntax::Inline,
+                    range: 0..16,
+                    section_anchor: None,
+                    original: "/bar".to_string(),
+                },
+                Link {
+                    title: "AltText".to_string(),
+                    url: UrlType::Local("/bar".into()),
+                    kind: LinkSyntax::Inline,
+                    range: 17..45,
+                    section_anchor: None,
+                    original: "/bar".to_string(),
+                },
+                Link {
+                    title: "AnHeader".to_string(),
+                    url: UrlType::Local("/bar".into()),
+                    kind: LinkSyntax::Inline,
+                    range: 49..65,
+                    section_anchor: Some("anheader".to_string()),
+                    original: "/bar".to_string(),
+                }
+            ]
         );
     }
 
     #[test]
-    fn appends_parameters_to_the_end_of_urls() {
+    fn detects_emojis() {
         let input = indoc! {"
-        [an link](relative/link)
+        I am :grinning:.
         "};
 
-        let mut options = ParseOptions::default();
-
-        options
-            .url_params
-            .insert("base".to_owned(), "123".to_owned());
+        let options = ParseOptions::default();
 
         let Markdown {
             as_html,
             headings: _headings,
-            links: _,
+            links: _links,
+            ..
         } = parse(&input, Some(options));
 
-        assert_eq!(
-            as_html,
-            indoc! {"
-                <p><a href=\"relative/link?base=123\">an link</a></p>
-            "}
-        );
+        assert_eq!(as_html, "<p>I am 😀.</p>\n");
     }
 
     #[test]
-    fn appends_multiple_parameters_to_the_end_of_urls() {
+    fn detects_emojis_in_links() {
         let input = indoc! {"
-        [an link](relative/link)
+        [:grinning:](/foo)
         "};
 
-        let mut options = ParseOptions::default();
-
-        options
-            .url_params
-            .insert("bases".to_owned(), "are".to_owned());
-        options
-            .url_params
-            .insert("belong".to_owned(), "tous".to_owned());
+        let options = ParseOptions::default();
 
         let Markdown {
             as_html,
             headings: _headings,
-            links: _,
+            links: _links,
+            ..
         } = parse(&input, Some(options));
 
-        assert!(as_html.contains("bases=are"));
-        assert!(as_html.contains("belong=tous"));
-        assert!(as_html.contains("&amp;"));
+        assert_eq!(as_html, "<p><a href=\"/foo\">😀</a></p>\n");
     }
 
     #[test]
-    fn appends_multiple_parameters_to_the_end_of_absolute_urls() {
+    fn leaves_emojis_in_code_alone() {
         let input = indoc! {"
-        [an link](/absolute/link)
-        "};
+        Use `:smile:` for :smile:.
 
-        let mut options = ParseOptions::default();
+        ```yaml
+        image: :latest:
+        ```
 
-        options
-            .url_params
-            .insert("base".to_owned(), "123".to_owned());
+            indented :smile:
 
-        let Markdown {
-            as_html,
-            headings: _headings,
-            links: _,
-        } = parse(&input, Some(options));
+        ```mermaid
+        A[:smile:]-->B
+        ```
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
 
         assert_eq!(
             as_html,
             indoc! {"
-                <p><a href=\"/absolute/link?base=123\">an link</a></p>
+                <p>Use <code>:smile:</code> for 😄.</p>
+                <pre><code class=\"language-yaml\">image: :latest:
+                </code></pre>
+                <pre><code class=\"code-indented\">indented :smile:
+                </code></pre>
+                <pre class=\"mermaid\">A[:smile:]--&gt;B
+                </pre>
             "}
         );
     }
 
     #[test]
-    fn does_not_append_params_to_urls_with_a_specific_domain() {
+    fn can_turn_off_emojis() {
         let input = indoc! {"
-        [an link](http://www.example.com/)
-        "};
+        # Roles :smile:
 
-        let mut options = ParseOptions::default();
+        Use :py:func: in [:grinning:](/foo).
+        "};
 
-        options
-            .url_params
-            .insert("bases".to_owned(), "are".to_owned());
-        options
-            .url_params
-            .insert("belong".to_owned(), "tous".to_owned());
+        let options = ParseOptions {
+            emoji: false,
+            ..ParseOptions::default()
+        };
 
         let Markdown {
             as_html,
-            headings: _headings,
-            links: _,
-        } = parse(&input, Some(options));
+            headings,
+            links,
+            ..
+        } = parse(input, Some(options));
 
         assert_eq!(
             as_html,
             indoc! {"
-                <p><a href=\"http://www.example.com/\">an link</a></p>
+                <h1 id=\"roles-:smile:\">Roles :smile:</h1>
+                <p>Use :py:func: in <a href=\"/foo\">:grinning:</a>.</p>
             "}
         );
+        assert_eq!(headings[0].title, "Roles :smile:");
+        assert_eq!(links[0].title, ":grinning:");
     }
 
     #[test]
-    fn sanitizes_input() {
+    fn uses_custom_emojis() {
         let input = indoc! {"
-        <script>
-        alert('I break you');
-        </script>
+        # Ready :shipit:
+
+        Ask [:oncall:](/oncall) about :smile: and :unknown:.
         "};
 
-        let options = ParseOptions::default();
+        let options = ParseOptions {
+            custom_emoji: vec![
+                ("shipit".to_string(), CustomEmoji::Text("🐿️".to_string())),
+                ("oncall".to_string(), CustomEmoji::Text("📟".to_string())),
+                ("smile".to_string(), CustomEmoji::Text("<:)>".to_string())),
+            ]
+            .into_iter()
+            .collect(),
+            ..ParseOptions::default()
+        };
 
         let Markdown {
             as_html,
-            headings: _headings,
-            links: _,
-        } = parse(&input, Some(options));
+            headings,
+            links,
+            ..
+        } = parse(input, Some(options));
 
-        assert_eq!(as_html, "\n");
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <h1 id=\"ready-🐿️\">Ready 🐿️</h1>
+                <p>Ask <a href=\"/oncall\">📟</a> about &lt;:)&gt; and :unknown:.</p>
+            "}
+        );
+        assert_eq!(headings[0].title, "Ready 🐿️");
+        assert_eq!(links[0].title, "📟");
     }
 
     #[test]
-    fn allows_mermaid_blocks() {
+    fn applies_skin_tones_to_emojis() {
         let input = indoc! {"
-        ```mermaid
-        graph TD;
-            A-->B;
-            A-->C;
-        ```
+        :wave::skin-tone-3: :+1::skin-tone-2: :v::skin-tone-6: :smile::skin-tone-4: :skin-tone-3: :wave: :skin-tone-3:
         "};
 
-        let options = ParseOptions::default();
-
-        let Markdown {
-            as_html,
-            headings: _headings,
-            links: _,
-        } = parse(&input, Some(options));
+        let Markdown { as_html, .. } = parse(input, None);
 
         assert_eq!(
             as_html,
-            indoc! {"
-        <div class=\"mermaid\">
-        graph TD;
-            A--&gt;B;
-            A--&gt;C;
-        </div>"}
+            "<p>👋🏼 👍🏻 ✌🏿 😄 :skin-tone-3: 👋 :skin-tone-3:</p>\n"
         );
     }
 
     #[test]
-    fn allows_code_blocks() {
+    fn does_not_warn_about_lone_skin_tones() {
         let input = indoc! {"
-        ```ruby
-        1 + 1
-        ```
+        hi :skin-tone-3: there :wave::skin-tone-3::skin-tone-4:
         "};
 
-        let options = ParseOptions::default();
+        let Markdown { warnings, .. } = parse(input, None);
 
-        let Markdown {
-            as_html,
-            headings: _headings,
-            links: _,
-        } = parse(&input, Some(options));
+        assert_eq!(warnings, vec![]);
+    }
+
+    #[test]
+    fn only_allocates_for_text_with_emojis() {
+        let options = ParseOptions::default();
 
+        assert!(matches!(
+            convert_emojis("No shortcodes here", &options, &mut Vec::new()),
+            Cow::Borrowed(_)
+        ));
+        assert!(matches!(
+            convert_emojis("I am :grinning:", &options, &mut Vec::new()),
+            Cow::Owned(_)
+        ));
         assert_eq!(
-            as_html,
-            indoc! {"
-        <pre><code class=\"language-ruby\">1 + 1
-        </code></pre>
- "}
+            convert_emojis("I am :grinning:", &options, &mut Vec::new()),
+            "I am 😀"
         );
     }
 
     #[test]
-    fn gathers_a_list_of_links_on_the_page() {
+    fn warns_about_unknown_emojis() {
         let input = indoc! {"
-        [foo](/bar)
+        Hi :grining:, meet at 10:30:00 on https://example.com:8080/a:b or std::io.
 
-        [Example](https://www.example.com)
+        `:nope:`
         "};
 
-        let options = ParseOptions::default();
-
         let Markdown {
-            as_html: _as_html,
-            headings: _headings,
-            links,
-        } = parse(&input, Some(options));
+            as_html, warnings, ..
+        } = parse(input, None);
 
         assert_eq!(
-            links,
-            vec![
-                Link {
-                    title: "foo".to_string(),
-                    url: UrlType::Local("/bar".into())
-                },
-                Link {
-                    title: "Example".to_string(),
-                    url: UrlType::Remote(Url::parse("https://www.example.com").unwrap())
-                }
-            ]
+            as_html,
+            indoc! {"
+                <p>Hi :grining:, meet at 10:30:00 on https://example.com:8080/a:b or std::io.</p>
+                <p><code>:nope:</code></p>
+            "}
+        );
+        assert_eq!(
+            warnings,
+            vec![Warning {
+                kind: WarningKind::UnknownEmoji("grining".to_string()),
+                range: 3..12,
+            }]
         );
+        assert_eq!(&input[warnings[0].range.clone()], ":grining:");
     }
 
     #[test]
-    fn gathers_the_internal_text_of_a_link() {
+    fn renders_custom_emoji_images() {
         let input = indoc! {"
-        [**BOLD**](/bar)
-        [![AltText](/src/foo)](/bar)
-        ## [AnHeader](/bar)
+        # Deploy :shipit: now
+
+        Ship it :shipit::shipit: :smile: ![:shipit:](/alt.png)
         "};
 
-        let options = ParseOptions::default();
+        let options = ParseOptions {
+            custom_emoji: vec![(
+                "shipit".to_string(),
+                CustomEmoji::Image("/emoji/shipit.png".to_string()),
+            )]
+            .into_iter()
+            .collect(),
+            image_root: Some("/assets/".to_string()),
+            ..ParseOptions::default()
+        };
 
         let Markdown {
-            as_html: _as_html,
-            headings: _headings,
-            links,
-        } = parse(&input, Some(options));
+            as_html,
+            headings,
+            warnings,
+            ..
+        } = parse(input, Some(options));
 
         assert_eq!(
-            links,
-            vec![
-                Link {
-                    title: "BOLD".to_string(),
-                    url: UrlType::Local("/bar".into())
-                },
-                Link {
-                    title: "AltText".to_string(),
-                    url: UrlType::Local("/bar".into())
-                },
-                Link {
-                    title: "AnHeader".to_string(),
-                    url: UrlType::Local("/bar".into())
-                }
-            ]
+            as_html,
+            indoc! {"
+                <h1 id=\"deploy-now\">Deploy <img class=\"custom-emoji\" src=\"/assets/emoji/shipit.png\" alt=\":shipit:\"> now</h1>
+                <p>Ship it <img class=\"custom-emoji\" src=\"/assets/emoji/shipit.png\" alt=\":shipit:\"><img class=\"custom-emoji\" src=\"/assets/emoji/shipit.png\" alt=\":shipit:\"> 😄 <img src=\"/assets/alt.png\" alt=\":shipit:\"></p>
+            "}
         );
+        assert_eq!(headings[0].anchor, "deploy-now");
+        assert_eq!(warnings, vec![]);
     }
 
     #[test]
-    fn detects_emojis() {
+    fn leaves_custom_emoji_images_out_of_heading_titles() {
         let input = indoc! {"
-        I am :grinning:.
+        # Deploy :shipit: now
+
+        ## :shipit: Ship it :shipit:
         "};
 
-        let options = ParseOptions::default();
+        let options = ParseOptions {
+            custom_emoji: vec![(
+                "shipit".to_string(),
+                CustomEmoji::Image("/emoji/shipit.png".to_string()),
+            )]
+            .into_iter()
+            .collect(),
+            ..ParseOptions::default()
+        };
 
-        let Markdown {
-            as_html,
-            headings: _headings,
-            links: _links,
-        } = parse(&input, Some(options));
+        let Markdown { headings, .. } = parse(input, Some(options));
 
-        assert_eq!(as_html, "<p>I am 😀.</p>\n");
+        assert_eq!(headings[0].title, "Deploy now");
+        assert_eq!(headings[0].anchor, "deploy-now");
+        assert_eq!(headings[1].title, "Ship it");
+        assert_eq!(headings[1].anchor, "ship-it");
     }
 
     #[test]
-    fn detects_emojis_in_links() {
+    fn understands_github_emoji_shortcodes() {
         let input = indoc! {"
-        [:grinning:](/foo)
+        :+1: :-1: :100: *:+1:* a:-1:b :shipit: :rage2: :+1::skin-tone-2:
         "};
 
-        let options = ParseOptions::default();
-
         let Markdown {
-            as_html,
-            headings: _headings,
-            links: _links,
-        } = parse(&input, Some(options));
+            as_html, warnings, ..
+        } = parse(input, None);
 
-        assert_eq!(as_html, "<p><a href=\"/foo\">😀</a></p>\n");
+        assert_eq!(as_html, "<p>👍 👎 💯 <em>👍</em> a👎b 🐿️ 😡 👍🏻</p>\n");
+        assert_eq!(warnings, vec![]);
+    }
+
+    #[test]
+    fn converts_each_github_alias() {
+        let aliases = [
+            (":+1:", "👍"),
+            (":thumbsup:", "👍"),
+            (":-1:", "👎"),
+            (":thumbsdown:", "👎"),
+            (":100:", "💯"),
+            (":1234:", "🔢"),
+            (":zero:", "0️⃣"),
+            (":one:", "1️⃣"),
+            (":nine:", "9️⃣"),
+            (":keycap_ten:", "🔟"),
+            (":shipit:", "🐿️"),
+        ];
+
+        for (shortcode, emoji) in aliases.iter() {
+            let mut unknown = Vec::new();
+
+            assert_eq!(
+                convert_emojis(shortcode, &ParseOptions::default(), &mut unknown),
+                *emoji
+            );
+            assert!(unknown.is_empty());
+        }
     }
 
     #[test]
@@ -977,6 +9644,7 @@ mod test {
             as_html,
             headings: _headings,
             links: _links,
+            ..
         } = parse(&input, Some(options));
 
         assert_eq!(as_html, "<p>Look at this :idonotexist:</p>\n");
@@ -994,6 +9662,7 @@ mod test {
             as_html,
             headings: _headings,
             links: _links,
+            ..
         } = parse(&input, Some(options));
 
         assert_eq!(as_html, "<p>Look at this :stop</p>\n");
@@ -1011,6 +9680,7 @@ mod test {
             as_html,
             headings: _headings,
             links: _links,
+            ..
         } = parse(&input, Some(options));
 
         assert_eq!(as_html, "<p>Look at this :stop MORE</p>\n");
@@ -1056,6 +9726,7 @@ mod test {
                 as_html,
                 headings: _headings,
                 links: _links,
+                ..
             } = parse(&input, Some(options));
 
             let expected = formatdoc! {"
@@ -1082,6 +9753,7 @@ mod test {
             as_html,
             headings: _headings,
             links: _links,
+            ..
         } = parse(&input, Some(options));
 
         let expected = indoc! {"
@@ -1108,6 +9780,7 @@ mod test {
             as_html,
             headings: _headings,
             links: _links,
+            ..
         } = parse(&input, Some(options));
 
         let expected = indoc! {"
@@ -1138,6 +9811,7 @@ mod test {
             as_html,
             headings: _headings,
             links: _links,
+            ..
         } = parse(&input, Some(options));
 
         let expected = indoc! {"
@@ -1171,6 +9845,7 @@ mod test {
             as_html,
             headings: _headings,
             links: _links,
+            ..
         } = parse(&input, Some(options));
 
         let expected = indoc! {"
@@ -1195,6 +9870,7 @@ mod test {
             as_html,
             headings: _headings,
             links: _links,
+            ..
         } = parse(&input, Some(options));
 
         let expected = indoc! {"
@@ -1214,6 +9890,40 @@ mod test {
         assert_matches(&as_html, &expected);
     }
 
+    #[test]
+    fn keeps_nested_task_list_checkboxes() {
+        let input = indoc! {"
+        * [x] Done
+          * [ ] Sub task
+          * [x] Sub done
+        * [ ] Todo
+
+        <input type=\"text\" value=\"secret\" onclick=\"go()\">
+        "};
+
+        let Markdown { as_html, .. } = parse(input, None);
+
+        assert_eq!(
+            as_html,
+            indoc! {"
+                <ul>
+                <li><input disabled=\"\" type=\"checkbox\" checked=\"\">
+                Done
+                <ul>
+                <li><input disabled=\"\" type=\"checkbox\">
+                Sub task</li>
+                <li><input disabled=\"\" type=\"checkbox\" checked=\"\">
+                Sub done</li>
+                </ul>
+                </li>
+                <li><input disabled=\"\" type=\"checkbox\">
+                Todo</li>
+                </ul>
+                <input type=\"checkbox\">
+            "}
+        );
+    }
+
     #[test]
     fn does_not_allow_random_forms() {
         let input = indoc! {"
@@ -1231,6 +9941,7 @@ mod test {
             as_html,
             headings: _headings,
             links: _links,
+            ..
         } = parse(&input, Some(options));
 
         let expected = "";
@@ -1255,6 +9966,7 @@ mod test {
             as_html,
             headings: _headings,
             links: _links,
+            ..
         } = parse(&input, Some(options));
 
         // Not a regular language block
@@ -1276,6 +9988,7 @@ mod test {
             as_html: _as_html,
             links: _links,
             headings,
+            ..
         } = parse(&input, Some(options));
 
         let link = headings.get(0).unwrap();